@@ -1,21 +1,26 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use cpal::traits::*;
 use enigo::{Enigo, Key, Keyboard, Settings};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs::{create_dir_all, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use dirs::config_dir;
 use futures_util::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use regex::Regex;
 use reqwest::Client;
-use std::io::Write;
-use std::sync::{Arc, Mutex};
+use std::io::{BufWriter, IsTerminal, Read, Write};
+use std::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use tokio::sync::mpsc;
-use tracing::{error, info};
-use vosk::{Model, Recognizer};
+use tracing::{error, info, warn};
+use vosk::{Model, Recognizer, SpeakerModel};
 use dialoguer::Select;
 use zip::ZipArchive;
 use once_cell::sync::Lazy;
-use text2num::{Language, replace_numbers_in_text};
+use serde::{Deserialize, Serialize};
+use text2num::{Language, get_interpreter_for, replace_numbers_in_text, text2digits};
 
 #[derive(Parser)]
 #[command(name = "scriba")]
@@ -40,60 +45,735 @@ struct Cli {
     /// Force model selection even if model exists
     #[arg(long)]
     select_model: bool,
+    
+    /// Id of the model to load, downloading it if needed (e.g. vosk-model-small-en-us-0.15)
+    #[arg(long, value_name = "ID")]
+    model: Option<String>,
+    
+    /// Install the recommended model instead of prompting for one
+    #[arg(short, long)]
+    yes: bool,
+    
+    /// Audio to transcribe: the microphone, what the speakers play, or both mixed
+    #[arg(long, value_enum)]
+    source: Option<AudioSource>,
+    
+    /// Post-processing mode: symbols and digits for code, or punctuated sentences for prose
+    #[arg(long, value_enum)]
+    mode: Option<DictationMode>,
+    
+    /// Language profile used in code mode: generic, rust, python, javascript
+    /// or one defined in `config.toml`
+    #[arg(long)]
+    profile: Option<String>,
+    
+    /// Read raw PCM audio from a file instead of an audio device; `-` reads stdin
+    #[arg(long, value_name = "FILE", conflicts_with = "source")]
+    input: Option<String>,
+    
+    /// Sample format of --input; its sample rate is --sample-rate
+    #[arg(long, value_enum, default_value = "s16le")]
+    input_format: InputFormat,
+    
+    /// Number of interleaved channels in --input, downmixed to mono
+    #[arg(long, default_value = "1")]
+    input_channels: u16,
+    
+    /// Save the audio fed to the recognizer and a transcript to this directory
+    #[arg(long, value_name = "DIR")]
+    record: Option<PathBuf>,
+    
+    /// Write one WAV file per session or one per utterance
+    #[arg(long, value_enum, default_value = "session")]
+    record_mode: RecordMode,
+    
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum DictationMode {
+    /// Spoken numbers become digits and symbol names become symbols
+    Code,
+    /// Spoken punctuation, capitalized sentences and natural spacing
+    Prose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum InputFormat {
+    /// Signed 16-bit little-endian
+    S16le,
+    /// 32-bit float little-endian
+    F32le,
+}
+
+impl InputFormat {
+    fn bytes_per_sample(self) -> usize {
+        match self {
+            InputFormat::S16le => 2,
+            InputFormat::F32le => 4,
+        }
+    }
+    
+    fn decode(self, bytes: &[u8]) -> f32 {
+        match self {
+            InputFormat::S16le => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            InputFormat::F32le => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum RecordMode {
+    Session,
+    Utterance,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check the microphone with a live input level meter
+    MicTest {
+        /// How long to listen, in seconds
+        #[arg(long, default_value = "10")]
+        seconds: u64,
+        
+        /// Save the captured audio to a WAV file
+        #[arg(long, value_name = "FILE")]
+        record: Option<PathBuf>,
+        
+        /// Play the captured audio back when done
+        #[arg(long)]
+        playback: bool,
+        
+        /// Transcribe the captured audio with the current model when done
+        #[arg(long)]
+        transcribe: bool,
+    },
+    
+    /// Learn the identifiers of a code repository as vocabulary
+    Learn {
+        /// Repository to scan
+        path: PathBuf,
+        
+        /// Vocabulary file to write; `vocabulary/<repository>-<hash>.txt`
+        /// in the config directory by default, which is loaded automatically
+        #[arg(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+        
+        /// Skip identifiers seen fewer times than this
+        #[arg(long, default_value = "2")]
+        min_count: usize,
+        
+        /// Keep at most this many terms, the most frequent ones
+        #[arg(long, default_value = "5000")]
+        max_entries: usize,
+    },
+    
+    /// Switch the language profile of the running instance
+    Profile {
+        /// Name of the profile to switch to
+        name: String,
+    },
+    
+    /// Record a speaker so that speaker identification labels them by name
+    EnrollSpeaker {
+        /// Name used to label this speaker's transcriptions
+        name: String,
+        
+        /// How long to record, in seconds
+        #[arg(long, default_value = "20")]
+        seconds: u64,
+    },
+}
+
+/// User settings read from `config.toml` in the scriba config directory.
+/// Every key can also be set through a `SCRIBA_`-prefixed environment variable.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Config {
+    /// Id of the model to load, e.g. `vosk-model-en-us-0.22-lgraph`
+    model: Option<String>,
+    /// Language code of the model, e.g. `de`, for models that aren't in the
+    /// catalog; picks the fillers to remove
+    language: Option<String>,
+    /// Post-processing mode, overridden by `--mode`
+    mode: Option<DictationMode>,
+    download: DownloadConfig,
+    audio: AudioConfig,
+    vad: VadConfig,
+    dsp: DspConfig,
+    speakers: SpeakersConfig,
+    vocabulary: VocabularyConfig,
+    cleanup: CleanupConfig,
+    /// Language profile used in code mode, overridden by `--profile`
+    profile: Option<String>,
+    /// Language profiles (`[profiles.<name>]` in `config.toml`), extending
+    /// the built-in ones with the same name
+    profiles: HashMap<String, ProfileConfig>,
+    /// Snippets available in every language profile (`[[snippets]]` in `config.toml`)
+    snippets: Vec<SnippetConfig>,
+    /// Mode and profile to use while a given window is focused
+    /// (`[[window_rules]]` in `config.toml`), first match wins
+    window_rules: Vec<WindowRule>,
+}
+
+/// Audio capture settings (`[audio]` in `config.toml`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct AudioConfig {
+    /// Audio to transcribe, overridden by `--source`
+    source: AudioSource,
+    /// Name of the input device; the default input device when unset
+    device: Option<String>,
+    /// Name of the monitor or loopback device capturing what the speakers
+    /// play; detected automatically when unset
+    system_device: Option<String>,
+    /// Capacity of the buffer between audio capture and recognition
+    buffer_ms: u64,
+    /// What to do when recognition falls behind and the buffer is full
+    overflow: OverflowPolicy,
+    /// Samples handed to the recognizer at once; smaller chunks lower the
+    /// latency of partial results at the cost of more decoder calls
+    chunk_size: usize,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            source: AudioSource::Mic,
+            device: None,
+            system_device: None,
+            buffer_ms: 2000,
+            overflow: OverflowPolicy::DropOldest,
+            chunk_size: 4000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum AudioSource {
+    /// The microphone
+    Mic,
+    /// What the speakers play, e.g. video calls and screencasts
+    System,
+    /// The microphone and the speakers, mixed
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum OverflowPolicy {
+    /// Discard the oldest buffered audio to keep latency bounded
+    DropOldest,
+    /// Make the capture callback wait for the recognizer, for at most
+    /// `MAX_BLOCK_WAIT` before dropping the oldest audio after all
+    Block,
+}
+
+/// Network settings for model downloads (`[download]` in `config.toml`)
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct DownloadConfig {
+    /// Base URL replacing `https://alphacephei.com/vosk/models` in model URLs
+    mirror: Option<String>,
+    /// HTTP(S) proxy, e.g. `http://proxy.example.com:3128`
+    proxy: Option<String>,
+    /// Extra PEM root certificates to trust, e.g. a corporate CA bundle
+    ca_certificates: Vec<PathBuf>,
+    connect_timeout_secs: u64,
+    /// Maximum time without receiving any data before the download is aborted
+    read_timeout_secs: u64,
+    /// Number of retries after a failed download attempt
+    retries: u32,
+    /// Delay before the first retry, doubled on every further attempt
+    retry_backoff_ms: u64,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        DownloadConfig {
+            mirror: None,
+            proxy: None,
+            ca_certificates: Vec::new(),
+            connect_timeout_secs: 30,
+            read_timeout_secs: 60,
+            retries: 3,
+            retry_backoff_ms: 1000,
+        }
+    }
+}
+
+impl DownloadConfig {
+    fn build_client(&self) -> Result<Client, Box<dyn std::error::Error>> {
+        let mut builder = Client::builder()
+            .connect_timeout(Duration::from_secs(self.connect_timeout_secs))
+            .read_timeout(Duration::from_secs(self.read_timeout_secs));
+        
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        
+        for path in &self.ca_certificates {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("Failed to read CA certificate {}: {}", path.display(), e))?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        
+        Ok(builder.build()?)
+    }
+    
+    /// Rewrite an upstream model URL to point at the configured mirror
+    fn resolve_url(&self, url: &str) -> String {
+        match &self.mirror {
+            Some(mirror) => match url.strip_prefix(MODELS_BASE_URL) {
+                Some(path) => format!("{}{}", mirror.trim_end_matches('/'), path),
+                None => url.to_string(),
+            },
+            None => url.to_string(),
+        }
+    }
+}
+
+impl Config {
+    fn load(config_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let settings = config::Config::builder()
+            .add_source(config::File::from(config_path.join("config.toml")).required(false))
+            .add_source(
+                config::Environment::with_prefix("SCRIBA")
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .build()?;
+        
+        Ok(settings.try_deserialize()?)
+    }
+}
+
+/// Voice activity detection settings (`[vad]` in `config.toml`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct VadConfig {
+    /// Off by default: every captured chunk reaches the recognizer
+    enabled: bool,
+    /// RMS level, in dBFS, above which a chunk is considered speech
+    threshold_db: f32,
+    /// How long audio keeps being fed to the recognizer after speech stops
+    hangover_ms: u64,
+    /// Audio kept from before speech is detected and fed along with it, so
+    /// quiet word onsets aren't cut off
+    pre_roll_ms: u64,
+    /// Silence after speech that ends the current utterance
+    silence_timeout_ms: u64,
+}
+
+impl Default for VadConfig {
+    fn default() -> Self {
+        VadConfig {
+            enabled: false,
+            threshold_db: -45.0,
+            hangover_ms: 300,
+            pre_roll_ms: 250,
+            silence_timeout_ms: 800,
+        }
+    }
+}
+
+/// Optional clean-up of the captured audio (`[dsp]` in `config.toml`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct DspConfig {
+    /// Remove rumble and DC offset below `high_pass_cutoff_hz`
+    high_pass: bool,
+    high_pass_cutoff_hz: f32,
+    /// Silence blocks quieter than `noise_gate_threshold_db`
+    noise_gate: bool,
+    noise_gate_threshold_db: f32,
+    /// Automatic gain control towards `agc_target_db`
+    agc: bool,
+    agc_target_db: f32,
+    agc_max_gain_db: f32,
+}
+
+impl Default for DspConfig {
+    fn default() -> Self {
+        DspConfig {
+            high_pass: false,
+            high_pass_cutoff_hz: 80.0,
+            noise_gate: false,
+            noise_gate_threshold_db: -55.0,
+            agc: false,
+            agc_target_db: -20.0,
+            agc_max_gain_db: 30.0,
+        }
+    }
+}
+
+/// Disfluency removal settings (`[cleanup]` in `config.toml`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct CleanupConfig {
+    /// Remove hesitation sounds such as "um" and "uh"
+    remove_fillers: bool,
+    /// Collapse immediately repeated phrases of two or more words in prose mode
+    collapse_repetitions: bool,
+    /// Extra fillers by model language code, e.g. `en = ["you know"]`
+    fillers: HashMap<String, Vec<String>>,
+}
+
+impl Default for CleanupConfig {
+    fn default() -> Self {
+        CleanupConfig {
+            remove_fillers: true,
+            collapse_repetitions: true,
+            fillers: HashMap::new(),
+        }
+    }
+}
+
+/// Project vocabulary settings (`[vocabulary]` in `config.toml`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct VocabularyConfig {
+    enabled: bool,
+    /// Vocabulary files read in addition to `vocabulary.txt` in the config directory
+    files: Vec<PathBuf>,
+    /// Smallest similarity, from 0 to 1, between what was heard and a term
+    /// for the term to be typed instead
+    threshold: f64,
+    /// Only recognize the vocabulary terms made of words the model knows,
+    /// for command-style dictation: any other speech is dropped. Vosk can't
+    /// add words at runtime, so unknown terms are left to correction. Needs a
+    /// model with a dynamic graph, such as the small and lgraph ones.
+    command_only: bool,
+}
+
+impl Default for VocabularyConfig {
+    fn default() -> Self {
+        VocabularyConfig {
+            enabled: true,
+            files: Vec::new(),
+            threshold: 0.85,
+            command_only: false,
+        }
+    }
+}
+
+/// Speaker identification settings (`[speakers]` in `config.toml`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct SpeakersConfig {
+    /// Tag final results with a speaker label using the Vosk speaker model
+    enabled: bool,
+    /// Largest cosine distance between speaker vectors considered the same speaker
+    max_distance: f32,
+}
+
+impl Default for SpeakersConfig {
+    fn default() -> Self {
+        SpeakersConfig {
+            enabled: false,
+            max_distance: 0.55,
+        }
+    }
+}
+
+/// Dictation settings for the windows a rule matches
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct WindowRule {
+    /// Regex matched against the window class (X11 `WM_CLASS`, Wayland app id), ignoring case
+    class: Option<String>,
+    /// Regex matched against the window title, ignoring case
+    title: Option<String>,
+    mode: Option<DictationMode>,
+    profile: Option<String>,
+    /// Set to false to only print transcriptions while these windows are focused
+    typing: Option<bool>,
+}
+
+/// Language profile settings (`[profiles.<name>]` in `config.toml`)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct ProfileConfig {
+    /// Replaces the spacing rules of the built-in profile
+    spacing: Option<SpacingRules>,
+    /// Spoken phrase → text, e.g. `"fat arrow" = "=>"`
+    symbols: BTreeMap<String, String>,
+    /// Words always typed with this casing, e.g. `None` in Python. Every
+    /// word spelled the same is cased, so only list words that are never
+    /// meant in lower case.
+    keywords: Vec<String>,
+    /// How names spoken as several words are joined in snippets
+    identifier_case: Option<IdentifierCase>,
+    /// Snippets only available in this profile, taking precedence over the
+    /// global and built-in ones
+    snippets: Vec<SnippetConfig>,
+}
+
+/// A snippet (`[[snippets]]` or `[[profiles.<name>.snippets]]` in `config.toml`)
+#[derive(Debug, Clone, Deserialize)]
+struct SnippetConfig {
+    /// Spoken form, with `{field}` capturing spoken words, e.g. "insert for loop over {items}"
+    trigger: String,
+    /// Text typed, with `{field}` filled from the trigger and `$0` the cursor position
+    template: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum IdentifierCase {
+    /// `foo_bar`
+    Snake,
+    /// `fooBar`
+    Camel,
+    /// `FooBar`
+    Pascal,
+}
+
+impl IdentifierCase {
+    fn apply(self, words: &str) -> String {
+        let mut identifier = String::new();
+        for (i, word) in words.split_whitespace().enumerate() {
+            match self {
+                IdentifierCase::Snake => {
+                    if i > 0 {
+                        identifier.push('_');
+                    }
+                    identifier.push_str(word);
+                }
+                IdentifierCase::Camel if i == 0 => identifier.push_str(word),
+                IdentifierCase::Camel | IdentifierCase::Pascal => {
+                    let mut chars = word.chars();
+                    if let Some(first) = chars.next() {
+                        identifier.extend(first.to_uppercase());
+                        identifier.push_str(chars.as_str());
+                    }
+                }
+            }
+        }
+        identifier
+    }
+}
+
+/// How symbols are joined to the surrounding words in code mode
+/// (`[profiles.<name>.spacing]` in `config.toml`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct SpacingRules {
+    /// Spaces around binary operators such as `=`, `+` and `==`
+    around_operators: bool,
+    after_comma: bool,
+    after_colon: bool,
+    inside_parens: bool,
+    inside_brackets: bool,
+    inside_braces: bool,
+    /// Words followed by a space before `(`, such as `if`; any other word
+    /// followed by `(` is a call and isn't
+    keywords_before_paren: Vec<String>,
+}
+
+impl Default for SpacingRules {
+    fn default() -> Self {
+        SpacingRules {
+            around_operators: true,
+            after_comma: true,
+            after_colon: true,
+            inside_parens: false,
+            inside_brackets: false,
+            inside_braces: false,
+            keywords_before_paren: ["if", "for", "while", "switch", "catch", "return"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        }
+    }
 }
 
 struct TranscriptionResult {
     text: String,
     confidence: f64,
     is_final: bool,
+    /// Set on final results when speaker identification is enabled
+    speaker: Option<SpeakerTag>,
+}
+
+/// Who said a final result
+#[derive(Debug, Clone)]
+struct SpeakerTag {
+    /// Enrolled name, or `Speaker N` for a speaker that isn't enrolled
+    label: String,
+    /// Cluster of a speaker that isn't enrolled; `None` for enrolled ones
+    cluster: Option<usize>,
+    /// x-vector computed by the speaker model
+    vector: Vec<f32>,
+    /// Number of non-silent frames the vector was computed over
+    frames: u16,
+}
+
+const SPEAKERS_FILE: &str = "speakers.json";
+
+/// Speaker vectors recorded with `scriba enroll-speaker`, stored as
+/// `speakers.json` in the config directory
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct EnrolledSpeakers {
+    speakers: BTreeMap<String, Vec<f32>>,
+}
+
+impl EnrolledSpeakers {
+    fn load(config_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = config_path.join(SPEAKERS_FILE);
+        if !path.exists() {
+            return Ok(EnrolledSpeakers::default());
+        }
+        
+        let contents = std::fs::read_to_string(&path)?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid speakers file {}: {}", path.display(), e).into())
+    }
+    
+    fn save(&self, config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = serde_json::to_string_pretty(self)?;
+        std::fs::write(config_path.join(SPEAKERS_FILE), contents)?;
+        Ok(())
+    }
+}
+
+struct SpeakerCluster {
+    centroid: Vec<f32>,
+    count: u32,
+}
+
+/// Labels speaker vectors: enrolled speakers by name, everyone else by
+/// clustering them online into `Speaker 1`, `Speaker 2`, ...
+struct SpeakerRegistry {
+    max_distance: f32,
+    enrolled: Vec<(String, Vec<f32>)>,
+    clusters: Vec<SpeakerCluster>,
+}
+
+impl SpeakerRegistry {
+    fn new(enrolled: EnrolledSpeakers, max_distance: f32) -> Self {
+        SpeakerRegistry {
+            max_distance,
+            enrolled: enrolled.speakers.into_iter().collect(),
+            clusters: Vec::new(),
+        }
+    }
+    
+    /// Returns the label and, for speakers that aren't enrolled, the cluster
+    fn identify(&mut self, vector: &[f32]) -> (String, Option<usize>) {
+        let closest_enrolled = self.enrolled.iter()
+            .map(|(name, enrolled)| (name, cosine_distance(vector, enrolled)))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((name, distance)) = closest_enrolled {
+            if distance <= self.max_distance {
+                return (name.clone(), None);
+            }
+        }
+        
+        let closest_cluster = self.clusters.iter()
+            .enumerate()
+            .map(|(i, cluster)| (i, cosine_distance(vector, &cluster.centroid)))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let index = match closest_cluster {
+            Some((i, distance)) if distance <= self.max_distance => {
+                // Move the centroid towards the new vector (running mean)
+                let cluster = &mut self.clusters[i];
+                cluster.count += 1;
+                let weight = 1.0 / cluster.count as f32;
+                for (c, v) in cluster.centroid.iter_mut().zip(vector) {
+                    *c += (v - *c) * weight;
+                }
+                i
+            }
+            _ => {
+                self.clusters.push(SpeakerCluster {
+                    centroid: vector.to_vec(),
+                    count: 1,
+                });
+                self.clusters.len() - 1
+            }
+        };
+        
+        (format!("Speaker {}", index + 1), Some(index))
+    }
+}
+
+fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 1.0;
+    }
+    1.0 - dot / (norm_a * norm_b)
 }
 
+/// Owns the Vosk recognizer. Decoding is CPU-heavy, so it lives on the
+/// dedicated decoder thread rather than on the async runtime.
 struct AudioProcessor {
-    recognizer: Arc<Mutex<Recognizer>>,
+    recognizer: Recognizer,
+    speakers: Option<SpeakerRegistry>,
+    /// Whether the last utterance ended without any text
+    discarded_final: bool,
 }
 
 impl AudioProcessor {
-    fn new(model: &Model, sample_rate: f32) -> Result<Self, Box<dyn std::error::Error>> {
-        let recognizer = Recognizer::new(model, sample_rate)
-            .ok_or("Failed to create Vosk recognizer")?;
+    /// With a `grammar`, only its phrases are recognized, when the model
+    /// supports it
+    fn new(model: &Model, sample_rate: f32, grammar: Option<&[String]>) -> Result<Self, Box<dyn std::error::Error>> {
+        let with_grammar = grammar.and_then(|phrases| {
+            let mut phrases = phrases.to_vec();
+            phrases.push("[unk]".to_string());
+            let recognizer = Recognizer::new_with_grammar(model, sample_rate, &phrases);
+            if recognizer.is_none() {
+                warn!("The model doesn't support grammars, recognizing its whole vocabulary");
+            }
+            recognizer
+        });
+        let recognizer = match with_grammar {
+            Some(recognizer) => recognizer,
+            None => Recognizer::new(model, sample_rate).ok_or("Failed to create Vosk recognizer")?,
+        };
         
         Ok(AudioProcessor {
-            recognizer: Arc::new(Mutex::new(recognizer)),
+            recognizer,
+            speakers: None,
+            discarded_final: false,
         })
     }
     
-    fn process_audio(&self, audio_data: &[i16]) -> Result<Option<TranscriptionResult>, Box<dyn std::error::Error>> {
-        let mut recognizer = self.recognizer.lock().unwrap();
-        
-        let result = recognizer.accept_waveform(audio_data)?;
+    /// Compute a speaker vector for every final result. With a registry,
+    /// results are also labeled with the matching speaker.
+    fn enable_speakers(&mut self, speaker_model: &SpeakerModel, registry: Option<SpeakerRegistry>) {
+        self.recognizer.set_speaker_model(speaker_model);
+        self.speakers = registry;
+    }
+    
+    fn process_audio(&mut self, audio_data: &[i16]) -> Result<Option<TranscriptionResult>, Box<dyn std::error::Error>> {
+        let result = self.recognizer.accept_waveform(audio_data)?;
         
         match result {
             vosk::DecodingState::Finalized => {
-                let complete_result = recognizer.result();
-                if let Some(single_result) = complete_result.single() {
-                    let text = single_result.text.to_string();
-                    if !text.trim().is_empty() {
-                        let confidence = single_result.result.first()
-                            .map(|word| word.conf as f64)
-                            .unwrap_or(0.8);
-                        
-                        return Ok(Some(TranscriptionResult {
-                            text,
-                            confidence,
-                            is_final: true,
-                        }));
-                    }
-                }
+                let complete_result = self.recognizer.result();
+                let result = Self::final_transcription(complete_result, self.speakers.as_mut());
+                self.discarded_final = result.is_none();
+                return Ok(result);
             }
             vosk::DecodingState::Running => {
-                let partial_result = recognizer.partial_result();
-                let text = partial_result.partial.to_string();
+                let partial_result = self.recognizer.partial_result();
+                let text = without_unknown_words(partial_result.partial);
                 
                 if !text.trim().is_empty() {
                     return Ok(Some(TranscriptionResult {
                         text,
                         confidence: 0.5, // Partial results have lower confidence
                         is_final: false,
+                        speaker: None,
                     }));
                 }
             }
@@ -102,6 +782,141 @@ impl AudioProcessor {
         
         Ok(None)
     }
+    
+    /// Force the end of the current utterance, e.g. after a silence timeout
+    fn finish_utterance(&mut self) -> Option<TranscriptionResult> {
+        let complete_result = self.recognizer.final_result();
+        let result = Self::final_transcription(complete_result, self.speakers.as_mut());
+        self.discarded_final = result.is_none();
+        result
+    }
+    
+    /// Whether an utterance ended without any text since the last call
+    fn take_discarded_final(&mut self) -> bool {
+        std::mem::take(&mut self.discarded_final)
+    }
+    
+    fn final_transcription(complete_result: vosk::CompleteResult, speakers: Option<&mut SpeakerRegistry>) -> Option<TranscriptionResult> {
+        let single_result = complete_result.single()?;
+        let text = without_unknown_words(single_result.text);
+        if text.trim().is_empty() {
+            return None;
+        }
+        
+        let confidence = single_result.result.first()
+            .map(|word| word.conf as f64)
+            .unwrap_or(0.8);
+        
+        let speaker = single_result.speaker_info.map(|info| {
+            let (label, cluster) = match speakers {
+                Some(registry) => registry.identify(&info.vector),
+                None => ("unknown".to_string(), None),
+            };
+            SpeakerTag {
+                label,
+                cluster,
+                vector: info.vector,
+                frames: info.frames,
+            }
+        });
+        
+        Some(TranscriptionResult {
+            text,
+            confidence,
+            is_final: true,
+            speaker,
+        })
+    }
+}
+
+/// Drop the `[unk]` a grammar recognizer reports for speech outside its
+/// grammar, so it is never typed
+fn without_unknown_words(text: &str) -> String {
+    text.split_whitespace().filter(|word| *word != "[unk]").collect::<Vec<_>>().join(" ")
+}
+
+/// What to do with a chunk of audio according to the voice activity detector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VoiceActivity {
+    /// Feed the chunk to the recognizer
+    Speech,
+    /// Skip the chunk
+    Silence,
+    /// Skip the chunk and finalize the current utterance
+    EndOfUtterance,
+}
+
+/// Energy-based voice activity detector that keeps silence and background
+/// noise away from the recognizer
+struct VoiceActivityDetector {
+    config: VadConfig,
+    sample_rate: u32,
+    in_utterance: bool,
+    silence_ms: u64,
+    /// Most recent skipped audio, at most `pre_roll_ms` long
+    pre_roll: VecDeque<f32>,
+}
+
+impl VoiceActivityDetector {
+    fn new(config: VadConfig, sample_rate: u32) -> Self {
+        VoiceActivityDetector {
+            config,
+            sample_rate,
+            in_utterance: false,
+            silence_ms: 0,
+            pre_roll: VecDeque::new(),
+        }
+    }
+    
+    fn process(&mut self, chunk: &[f32]) -> VoiceActivity {
+        if !self.config.enabled {
+            return VoiceActivity::Speech;
+        }
+        
+        if rms_db(chunk) >= self.config.threshold_db {
+            self.in_utterance = true;
+            self.silence_ms = 0;
+            return VoiceActivity::Speech;
+        }
+        
+        let activity = if !self.in_utterance {
+            VoiceActivity::Silence
+        } else {
+            self.silence_ms += chunk.len() as u64 * 1000 / self.sample_rate.max(1) as u64;
+            if self.silence_ms >= self.config.silence_timeout_ms {
+                self.in_utterance = false;
+                VoiceActivity::EndOfUtterance
+            } else if self.silence_ms <= self.config.hangover_ms {
+                return VoiceActivity::Speech;
+            } else {
+                VoiceActivity::Silence
+            }
+        };
+        
+        let capacity = (self.config.pre_roll_ms * self.sample_rate as u64 / 1000) as usize;
+        self.pre_roll.extend(chunk);
+        let excess = self.pre_roll.len().saturating_sub(capacity);
+        self.pre_roll.drain(..excess);
+        
+        activity
+    }
+    
+    /// Take the skipped audio preceding the current speech chunk. Must be
+    /// fed to the recognizer before that chunk.
+    fn take_pre_roll(&mut self) -> Vec<f32> {
+        self.pre_roll.drain(..).collect()
+    }
+}
+
+/// RMS level of `samples` in dBFS
+fn rms_db(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    
+    let sum_squares: f32 = samples.iter().map(|s| s * s).sum();
+    let rms = (sum_squares / samples.len() as f32).sqrt();
+    20.0 * rms.max(f32::MIN_POSITIVE).log10()
 }
 
 struct TextTyper {
@@ -131,9 +946,25 @@ impl TextTyper {
                 self.clear_partial_text();
             }
             
-            // Type the final result
-            let _ = self.enigo.text(&result.text);
-            let _ = self.enigo.key(Key::Space, enigo::Direction::Click);
+            // Type the final result, already joined to the previous one by `OutputContext`
+            match result.text.split_once(CURSOR_MARKER) {
+                Some((before, after)) => {
+                    let _ = self.enigo.text(&format!("{}{}", before, after));
+                    let (up, left) = cursor_moves(after);
+                    for _ in 0..up {
+                        let _ = self.enigo.key(Key::UpArrow, enigo::Direction::Click);
+                    }
+                    if up > 0 {
+                        let _ = self.enigo.key(Key::End, enigo::Direction::Click);
+                    }
+                    for _ in 0..left {
+                        let _ = self.enigo.key(Key::LeftArrow, enigo::Direction::Click);
+                    }
+                }
+                None => {
+                    let _ = self.enigo.text(&result.text);
+                }
+            }
             self.last_partial.clear();
         } else {
             // Handle partial results (optional - can be distracting)
@@ -149,57 +980,733 @@ impl TextTyper {
     }
 }
 
-fn convert_f32_to_i16(input: &[f32]) -> Vec<i16> {
-    input.iter().map(|&sample| (sample * 32767.0) as i16).collect()
+/// Keys that bring the cursor back from the end of `after`, the text typed
+/// after it: lines up, then End and characters left on the cursor line.
+/// Only the cursor line is counted character by character, so whitespace an
+/// editor auto-indents the following lines with doesn't matter. Brackets
+/// or quotes the editor closes on its own can still shift the cursor, so
+/// placement is best-effort.
+fn cursor_moves(after: &str) -> (usize, usize) {
+    let cursor_line = after.split('\n').next().unwrap_or_default();
+    (after.matches('\n').count(), cursor_line.chars().count())
 }
 
-fn setup_audio_stream(sample_rate: u32, tx: mpsc::UnboundedSender<Vec<f32>>) -> Result<(), Box<dyn std::error::Error>> {
-    let host = cpal::default_host();
-    let device = host
-        .default_input_device()
-        .ok_or("No input device available")?;
-    
-    info!("Using input device: {}", device.name()?);
-    
-    let config = cpal::StreamConfig {
-        channels: 1,
-        sample_rate: cpal::SampleRate(sample_rate),
-        buffer_size: cpal::BufferSize::Default,
-    };
+/// Tracks the end of the text output so far, so that consecutive results
+/// are joined with the right spacing and prose sentences are capitalized.
+struct OutputContext {
+    last_char: Option<char>,
+}
 
-    let stream = device.build_input_stream(
-        &config,
-        move |data: &[f32], _: &cpal::InputCallbackInfo| {
-            if let Err(e) = tx.send(data.to_vec()) {
-                error!("Failed to send audio data: {}", e);
-            }
-        },
-        |err| {
-            error!("Audio stream error: {}", err);
-        },
-        None,
-    )?;
-    
-    stream.play()?;
+impl OutputContext {
+    fn new() -> Self {
+        OutputContext { last_char: None }
+    }
     
-    // Keep the stream alive
-    std::mem::forget(stream);
+    fn at_sentence_start(&self) -> bool {
+        match self.last_char {
+            None => true,
+            Some(c) => matches!(c, '.' | '?' | '!' | '\n'),
+        }
+    }
     
-    Ok(())
+    /// Prefix `text` with a space when it continues previous output
+    fn join(&mut self, text: &str) -> String {
+        let Some(first) = text.chars().next() else {
+            return String::new();
+        };
+        
+        let needs_space = self.last_char.is_some_and(|c| !c.is_whitespace() && c != '(' && c != '\n')
+            && !matches!(first, ',' | '.' | '?' | '!' | ':' | ';' | ')' | '\n');
+        // Output continues from the cursor, which a snippet may leave inside the text
+        let before_cursor = text.split(CURSOR_MARKER).next().unwrap_or_default();
+        self.last_char = before_cursor.chars().last().or(if needs_space { Some(' ') } else { self.last_char });
+        
+        if needs_space {
+            format!(" {}", text)
+        } else {
+            text.to_string()
+        }
+    }
 }
 
-#[derive(Clone)]
-struct ModelInfo {
-    name: String,
-    url: String,
-    size: &'static str,
-    description: &'static str,
-    language: &'static str,
+fn convert_f32_to_i16(input: &[f32]) -> Vec<i16> {
+    // Clamp first so that loud or amplified input clips instead of wrapping
+    input.iter().map(|&sample| (sample.clamp(-1.0, 1.0) * 32767.0).round() as i16).collect()
 }
 
-static AVAILABLE_MODELS: Lazy<Vec<ModelInfo>> = Lazy::new(|| vec![
-    // English Models
-    ModelInfo {
+/// Length of the blocks the noise gate and AGC measure levels over
+const DSP_BLOCK_MS: usize = 10;
+
+/// High-pass filter, noise gate and automatic gain control applied to the
+/// captured audio before recognition. Each stage is enabled in `DspConfig`.
+struct AudioFilterChain {
+    config: DspConfig,
+    block_size: usize,
+    high_pass_alpha: f32,
+    high_pass_prev_input: f32,
+    high_pass_prev_output: f32,
+    gate_gain: f32,
+    agc_gain: f32,
+}
+
+impl AudioFilterChain {
+    fn new(config: DspConfig, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * config.high_pass_cutoff_hz.max(1.0));
+        let dt = 1.0 / sample_rate.max(1) as f32;
+        
+        AudioFilterChain {
+            config,
+            block_size: (sample_rate as usize * DSP_BLOCK_MS / 1000).max(1),
+            high_pass_alpha: rc / (rc + dt),
+            high_pass_prev_input: 0.0,
+            high_pass_prev_output: 0.0,
+            gate_gain: 1.0,
+            agc_gain: 1.0,
+        }
+    }
+    
+    fn is_enabled(&self) -> bool {
+        self.config.high_pass || self.config.noise_gate || self.config.agc
+    }
+    
+    fn process(&mut self, samples: &mut [f32]) {
+        if self.config.high_pass {
+            self.high_pass(samples);
+        }
+        
+        if self.config.noise_gate || self.config.agc {
+            for block in samples.chunks_mut(self.block_size) {
+                let level = rms_db(block);
+                if self.config.noise_gate {
+                    self.noise_gate(block, level);
+                }
+                if self.config.agc {
+                    self.agc(block, level);
+                }
+            }
+        }
+    }
+    
+    /// One-pole high-pass filter
+    fn high_pass(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            let input = *sample;
+            let output = self.high_pass_alpha * (self.high_pass_prev_output + input - self.high_pass_prev_input);
+            self.high_pass_prev_input = input;
+            self.high_pass_prev_output = output;
+            *sample = output;
+        }
+    }
+    
+    fn noise_gate(&mut self, block: &mut [f32], level: f32) {
+        let target = if level >= self.config.noise_gate_threshold_db { 1.0 } else { 0.0 };
+        
+        // Ramp the gain across the block to avoid clicks when the gate moves
+        let step = (target - self.gate_gain) / block.len() as f32;
+        for sample in block.iter_mut() {
+            self.gate_gain += step;
+            *sample *= self.gate_gain;
+        }
+        self.gate_gain = target;
+    }
+    
+    fn agc(&mut self, block: &mut [f32], level: f32) {
+        // Hold the gain during silence instead of amplifying the noise floor
+        if level > -60.0 {
+            let max_gain = db_to_gain(self.config.agc_max_gain_db);
+            let desired = db_to_gain(self.config.agc_target_db - level).min(max_gain);
+            // Back off quickly when too loud, ramp up slowly when too quiet
+            let rate = if desired < self.agc_gain { 0.5 } else { 0.05 };
+            self.agc_gain += (desired - self.agc_gain) * rate;
+        }
+        
+        for sample in block.iter_mut() {
+            *sample = (*sample * self.agc_gain).clamp(-1.0, 1.0);
+        }
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Preallocated lock-free ring buffer of samples between the audio callback
+/// (single producer) and the recognizer (single consumer).
+///
+/// Positions only ever grow and are mapped onto the slots modulo the
+/// capacity. With `OverflowPolicy::DropOldest` the producer makes room by
+/// advancing the read position itself; the consumer notices when its
+/// compare-and-swap fails and retries with the newer samples.
+struct AudioRingBuffer {
+    slots: Box<[AtomicU32]>,
+    read_pos: AtomicUsize,
+    write_pos: AtomicUsize,
+    policy: OverflowPolicy,
+    overruns: AtomicU64,
+    dropped_samples: AtomicU64,
+    /// Set once the producer has pushed its last samples
+    closed: AtomicBool,
+}
+
+impl AudioRingBuffer {
+    fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        AudioRingBuffer {
+            slots: (0..capacity.max(1)).map(|_| AtomicU32::new(0)).collect(),
+            read_pos: AtomicUsize::new(0),
+            write_pos: AtomicUsize::new(0),
+            policy,
+            overruns: AtomicU64::new(0),
+            dropped_samples: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+    
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+    
+    /// Number of samples waiting to be read
+    fn available(&self) -> usize {
+        let read = self.read_pos.load(Ordering::Acquire);
+        let write = self.write_pos.load(Ordering::Acquire);
+        write.saturating_sub(read)
+    }
+    
+    /// Mark the end of the input, e.g. when stdin reaches EOF, or that the
+    /// recognizer stopped reading so producers must not wait for it
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+    }
+    
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+    
+    /// Number of samples that can be pushed without overflowing
+    fn free(&self) -> usize {
+        self.capacity() - self.available()
+    }
+    
+    /// Append samples; called from the audio callback only
+    fn push(&self, mut data: &[f32]) {
+        if data.len() > self.capacity() {
+            let excess = data.len() - self.capacity();
+            self.record_overrun(excess);
+            data = &data[excess..];
+        }
+        
+        let write = self.write_pos.load(Ordering::Relaxed);
+        let mut blocked_since = None;
+        loop {
+            let read = self.read_pos.load(Ordering::Acquire);
+            let free = self.capacity() - (write - read);
+            if free >= data.len() {
+                break;
+            }
+            
+            // Never wait on a recognizer that has exited or stalled
+            let drop_oldest = match self.policy {
+                OverflowPolicy::DropOldest => true,
+                OverflowPolicy::Block => {
+                    let since = *blocked_since.get_or_insert_with(Instant::now);
+                    self.is_closed() || since.elapsed() >= MAX_BLOCK_WAIT
+                }
+            };
+            
+            if !drop_oldest {
+                std::thread::yield_now();
+                continue;
+            }
+            
+            let needed = data.len() - free;
+            if self.read_pos
+                .compare_exchange(read, read + needed, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                // Pairs with the fence in `read_chunk` so a consumer copying
+                // slots we are about to overwrite sees its CAS fail
+                fence(Ordering::Release);
+                self.record_overrun(needed);
+                break;
+            }
+        }
+        
+        for (i, &sample) in data.iter().enumerate() {
+            self.slots[(write + i) % self.capacity()].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.write_pos.store(write + data.len(), Ordering::Release);
+    }
+    
+    /// Fill `out` with the oldest buffered samples if enough are available
+    fn read_chunk(&self, out: &mut [f32]) -> bool {
+        loop {
+            let read = self.read_pos.load(Ordering::Acquire);
+            let write = self.write_pos.load(Ordering::Acquire);
+            if write - read < out.len() {
+                return false;
+            }
+            
+            for (i, sample) in out.iter_mut().enumerate() {
+                *sample = f32::from_bits(self.slots[(read + i) % self.capacity()].load(Ordering::Relaxed));
+            }
+            
+            fence(Ordering::Acquire);
+            if self.read_pos
+                .compare_exchange(read, read + out.len(), Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return true;
+            }
+            // The producer dropped the samples we were copying; retry with newer ones
+        }
+    }
+    
+    /// Total number of samples ever pushed, used to notice a stalled stream
+    fn total_written(&self) -> usize {
+        self.write_pos.load(Ordering::Acquire)
+    }
+    
+    fn record_overrun(&self, samples: usize) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+        self.dropped_samples.fetch_add(samples as u64, Ordering::Relaxed);
+    }
+}
+
+/// Longest time `OverflowPolicy::Block` holds up the audio callback
+const MAX_BLOCK_WAIT: Duration = Duration::from_millis(100);
+
+/// Reads fixed-size chunks from one ring buffer per capture source and mixes
+/// them. The first source paces reading; whatever the others delivered is
+/// mixed in, and samples beyond the current chunk are kept for the next one
+/// so the sources stay aligned.
+struct AudioMixer {
+    sources: Vec<Arc<AudioRingBuffer>>,
+    /// Samples read from each source but not mixed in yet
+    leftovers: Vec<VecDeque<f32>>,
+    scratch: Vec<f32>,
+}
+
+impl AudioMixer {
+    fn new(sources: Vec<Arc<AudioRingBuffer>>) -> Self {
+        AudioMixer {
+            leftovers: vec![VecDeque::new(); sources.len()],
+            sources,
+            scratch: Vec::new(),
+        }
+    }
+    
+    fn read_chunk(&mut self, out: &mut [f32]) -> bool {
+        let Some((primary, others)) = self.sources.split_first() else {
+            return false;
+        };
+        if !primary.read_chunk(out) {
+            // Once the input has ended, pad what is left with silence
+            let remaining = primary.available();
+            if !primary.is_closed() || remaining == 0 || !primary.read_chunk(&mut out[..remaining]) {
+                return false;
+            }
+            out[remaining..].fill(0.0);
+        }
+        
+        for (ring, leftover) in others.iter().zip(&mut self.leftovers[1..]) {
+            self.scratch.resize(ring.available(), 0.0);
+            if !self.scratch.is_empty() && ring.read_chunk(&mut self.scratch) {
+                leftover.extend(&self.scratch);
+            }
+            // A source running faster than the first one must not build up latency
+            let excess = leftover.len().saturating_sub(ring.capacity());
+            if excess > 0 {
+                ring.record_overrun(excess);
+                leftover.drain(..excess);
+            }
+            
+            let mixed = out.len().min(leftover.len());
+            for (sample, other) in out.iter_mut().zip(leftover.drain(..mixed)) {
+                *sample = (*sample + other).clamp(-1.0, 1.0);
+            }
+        }
+        
+        true
+    }
+    
+    /// Whether the input has ended and everything has been read
+    fn is_finished(&self) -> bool {
+        self.sources.first().is_none_or(|ring| ring.is_closed() && ring.available() == 0)
+    }
+    
+    /// Tell the producers nothing is read anymore
+    fn close(&self) {
+        for ring in &self.sources {
+            ring.close();
+        }
+    }
+    
+    /// Overrun events and dropped samples, summed over all sources
+    fn overrun_stats(&self) -> (u64, u64) {
+        self.sources.iter().fold((0, 0), |(overruns, dropped), ring| {
+            (
+                overruns + ring.overruns.load(Ordering::Relaxed),
+                dropped + ring.dropped_samples.load(Ordering::Relaxed),
+            )
+        })
+    }
+}
+
+/// How often the recognizer checks the ring buffer when it is waiting for audio
+const AUDIO_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Minimum time between two overrun warnings
+const OVERRUN_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Logs ring buffer overruns, rate-limited so a struggling recognizer
+/// doesn't also flood the terminal
+struct OverrunReporter {
+    reported_overruns: u64,
+    reported_samples: u64,
+    last_report: Option<Instant>,
+}
+
+impl OverrunReporter {
+    fn new() -> Self {
+        OverrunReporter {
+            reported_overruns: 0,
+            reported_samples: 0,
+            last_report: None,
+        }
+    }
+    
+    fn check(&mut self, input: &AudioMixer, sample_rate: u32) {
+        if self.last_report.is_some_and(|last| last.elapsed() < OVERRUN_REPORT_INTERVAL) {
+            return;
+        }
+        
+        let (overruns, dropped) = input.overrun_stats();
+        if overruns == self.reported_overruns {
+            return;
+        }
+        
+        warn!(
+            "Audio buffer overrun: dropped {:.2}s of audio in {} overruns (total {}). Recognition is falling behind.",
+            (dropped - self.reported_samples) as f64 / sample_rate as f64,
+            overruns - self.reported_overruns,
+            overruns,
+        );
+        self.reported_overruns = overruns;
+        self.reported_samples = dropped;
+        self.last_report = Some(Instant::now());
+    }
+}
+
+/// Time without any captured audio after which the stream is considered dead
+const STREAM_WATCHDOG_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Longest wait between two reconnection attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Feed raw PCM from `path` (`-` for stdin) into `ring` on a dedicated
+/// thread, downmixing to mono, and close the ring at end of input.
+fn setup_raw_input(path: &str, format: InputFormat, channels: u16, ring: Arc<AudioRingBuffer>) -> Result<(), Box<dyn std::error::Error>> {
+    let reader: Box<dyn Read + Send> = if path == "-" {
+        Box::new(std::io::stdin())
+    } else {
+        Box::new(File::open(path).map_err(|e| format!("Failed to open input {}: {}", path, e))?)
+    };
+    
+    info!("Reading {:?} audio with {} channel(s) from {}", format, channels.max(1), if path == "-" { "stdin" } else { path });
+    spawn_raw_reader(reader, format, channels, ring)
+}
+
+fn spawn_raw_reader(mut reader: Box<dyn Read + Send>, format: InputFormat, channels: u16, ring: Arc<AudioRingBuffer>) -> Result<(), Box<dyn std::error::Error>> {
+    let bytes_per_sample = format.bytes_per_sample();
+    let channels = channels.max(1) as usize;
+    let frame_bytes = bytes_per_sample * channels;
+    
+    std::thread::Builder::new().name("scriba-input".to_string()).spawn(move || {
+        let mut buffer = vec![0u8; frame_bytes * 1024];
+        let mut pending = Vec::new();
+        let mut samples = Vec::with_capacity(1024);
+        
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to read audio input: {}", e);
+                    break;
+                }
+            };
+            
+            // Reads don't have to end on a frame boundary
+            pending.extend_from_slice(&buffer[..read]);
+            let whole_frames = pending.len() / frame_bytes * frame_bytes;
+            
+            samples.clear();
+            for frame in pending[..whole_frames].chunks_exact(frame_bytes) {
+                let sum: f32 = frame.chunks_exact(bytes_per_sample).map(|bytes| format.decode(bytes)).sum();
+                samples.push(sum / channels as f32);
+            }
+            pending.drain(..whole_frames);
+            
+            // Files are read faster than real time: wait for room here rather
+            // than in `push`, which gives up after `MAX_BLOCK_WAIT`
+            while ring.free() < samples.len() && !ring.is_closed() {
+                std::thread::sleep(AUDIO_POLL_INTERVAL);
+            }
+            if ring.is_closed() {
+                break;
+            }
+            ring.push(&samples);
+        }
+        
+        ring.close();
+    })?;
+    
+    Ok(())
+}
+
+/// Device an audio capture thread records from
+#[derive(Debug, Clone)]
+enum CaptureDevice {
+    /// The named input device, or the default input device
+    Microphone(Option<String>),
+    /// The named monitor/loopback device, or one detected automatically
+    SystemAudio(Option<String>),
+}
+
+impl CaptureDevice {
+    /// Remember the microphone actually opened so reconnects pick it again.
+    /// System audio is detected again instead, in case the monitor that was
+    /// found belonged to an output device that is gone.
+    fn remember(&mut self, name: String) {
+        if let CaptureDevice::Microphone(preferred) = self {
+            preferred.get_or_insert(name);
+        }
+    }
+}
+
+/// Start capturing audio into `ring` on a dedicated thread that owns the
+/// stream. If the device is unplugged or the audio server restarts, the
+/// stream is rebuilt on the same device (by name) or on the default one;
+/// the ring buffer, and so the recognizer state, is kept across reconnects.
+fn setup_audio_stream(sample_rate: u32, device: CaptureDevice, ring: Arc<AudioRingBuffer>) -> Result<(), Box<dyn std::error::Error>> {
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel::<Result<(), String>>();
+    
+    std::thread::Builder::new().name("scriba-audio".to_string()).spawn(move || {
+        let (error_tx, error_rx) = std::sync::mpsc::channel::<cpal::StreamError>();
+        let mut ready_tx = Some(ready_tx);
+        let mut device = device;
+        let mut delay = Duration::from_secs(1);
+        
+        loop {
+            let (stream, name) = match open_input_stream(sample_rate, &device, ring.clone(), error_tx.clone()) {
+                Ok(opened) => opened,
+                Err(e) => {
+                    if let Some(ready_tx) = ready_tx.take() {
+                        let _ = ready_tx.send(Err(e.to_string()));
+                        return;
+                    }
+                    warn!("Failed to reopen audio input: {}. Retrying in {}s", e, delay.as_secs());
+                    std::thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    continue;
+                }
+            };
+            
+            match ready_tx.take() {
+                Some(ready_tx) => {
+                    let _ = ready_tx.send(Ok(()));
+                }
+                None => {
+                    info!("🎤 Audio input reconnected: {}", name);
+                }
+            }
+            device.remember(name);
+            delay = Duration::from_secs(1);
+            
+            // Wait until the stream reports an error or stops delivering audio
+            let mut last_written = ring.total_written();
+            loop {
+                match error_rx.recv_timeout(STREAM_WATCHDOG_TIMEOUT) {
+                    Ok(err) => {
+                        warn!("⚠ Audio input lost ({}). Reconnecting...", err);
+                        break;
+                    }
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                        let written = ring.total_written();
+                        if written == last_written {
+                            warn!("⚠ Audio input stopped delivering audio. Reconnecting...");
+                            break;
+                        }
+                        last_written = written;
+                    }
+                    Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            
+            drop(stream);
+            // Discard errors queued by the stream that was just torn down
+            while error_rx.try_recv().is_ok() {}
+        }
+    })?;
+    
+    ready_rx.recv()
+        .map_err(|_| "Audio capture thread exited unexpectedly")?
+        .map_err(|e| e.into())
+}
+
+fn find_microphone(host: &cpal::Host, name: Option<&str>) -> Result<cpal::Device, Box<dyn std::error::Error>> {
+    if let Some(name) = name {
+        match host.input_devices()?.find(|d| d.name().is_ok_and(|n| n == name)) {
+            Some(device) => return Ok(device),
+            None => warn!("Input device '{}' not found, using the default input device", name),
+        }
+    }
+    
+    Ok(host
+        .default_input_device()
+        .ok_or("No input device available")?)
+}
+
+/// Find a device capturing what the speakers play
+fn find_system_audio_device(host: &cpal::Host, name: Option<&str>) -> Result<cpal::Device, Box<dyn std::error::Error>> {
+    if let Some(name) = name {
+        // Output devices can be captured directly on Windows (WASAPI loopback)
+        return host.input_devices()?
+            .chain(host.output_devices()?)
+            .find(|d| d.name().is_ok_and(|n| n == name))
+            .ok_or_else(|| format!("System audio device '{}' not found", name).into());
+    }
+    
+    let is_monitor = |device: &cpal::Device| device.name().is_ok_and(|n| {
+        let n = n.to_lowercase();
+        n.contains("monitor") || n.contains("loopback")
+    });
+    if let Some(device) = host.input_devices()?.find(is_monitor) {
+        return Ok(device);
+    }
+    
+    if cfg!(target_os = "windows") {
+        if let Some(device) = host.default_output_device() {
+            return Ok(device);
+        }
+    }
+    
+    Err("No monitor or loopback device found for system audio. Set `system_device` in the [audio] section of config.toml".into())
+}
+
+/// Build and start an input stream on `device`. Returns the name of the
+/// device actually used.
+fn open_input_stream(
+    sample_rate: u32,
+    device: &CaptureDevice,
+    ring: Arc<AudioRingBuffer>,
+    error_tx: std::sync::mpsc::Sender<cpal::StreamError>,
+) -> Result<(cpal::Stream, String), Box<dyn std::error::Error>> {
+    let host = cpal::default_host();
+    let device = match device {
+        CaptureDevice::Microphone(name) => find_microphone(&host, name.as_deref())?,
+        CaptureDevice::SystemAudio(name) => find_system_audio_device(&host, name.as_deref())?,
+    };
+    
+    let name = device.name()?;
+    info!("Using input device: {}", name);
+    
+    let config = cpal::StreamConfig {
+        channels: 1,
+        sample_rate: cpal::SampleRate(sample_rate),
+        buffer_size: cpal::BufferSize::Default,
+    };
+
+    let stream = device.build_input_stream(
+        &config,
+        move |data: &[f32], _: &cpal::InputCallbackInfo| {
+            ring.push(data);
+        },
+        move |err| {
+            error!("Audio stream error: {}", err);
+            let _ = error_tx.send(err);
+        },
+        None,
+    )?;
+    
+    stream.play()?;
+    
+    Ok((stream, name))
+}
+
+#[derive(Clone)]
+struct ModelInfo {
+    name: String,
+    url: String,
+    size: &'static str,
+    description: &'static str,
+    language: &'static str,
+}
+
+impl ModelInfo {
+    /// Stable identifier of the model, taken from its archive name
+    /// (e.g. `vosk-model-small-en-us-0.15`).
+    fn id(&self) -> &str {
+        let file_name = self.url.rsplit('/').next().unwrap_or(&self.url);
+        file_name.strip_suffix(".zip").unwrap_or(file_name)
+    }
+    
+    /// Directory under `models/` the model is downloaded into
+    fn dir_name(&self) -> String {
+        self.name.replace(' ', "_").to_lowercase()
+    }
+    
+    /// Language and region codes from the model id, e.g. `("en", Some("us"))`
+    /// for `vosk-model-small-en-us-0.15`
+    fn language_code(&self) -> (&str, Option<&str>) {
+        let rest = self.id().trim_start_matches("vosk-model-").trim_start_matches("small-");
+        let mut parts = rest.split('-');
+        let language = parts.next().unwrap_or("");
+        let region = parts.next().filter(|part| part.len() == 2 && part.chars().all(|c| c.is_ascii_alphabetic()));
+        (language, region)
+    }
+    
+    /// Approximate download size in bytes, parsed from `size`
+    fn size_bytes(&self) -> u64 {
+        let (number, unit) = self.size.split_at(self.size.len().saturating_sub(2));
+        let multiplier = match unit {
+            "GB" => 1_000_000_000.0,
+            "MB" => 1_000_000.0,
+            "KB" => 1_000.0,
+            _ => 1.0,
+        };
+        (number.parse::<f64>().unwrap_or(0.0) * multiplier) as u64
+    }
+}
+
+/// Speech model with the given id; never the speaker model
+fn find_model_info(id: &str) -> Option<&'static ModelInfo> {
+    AVAILABLE_MODELS.iter().find(|m| m.id() == id)
+}
+
+/// Any model Scriba can install, including the speaker model
+fn find_any_model_info(id: &str) -> Option<&'static ModelInfo> {
+    AVAILABLE_MODELS.iter()
+        .chain(std::iter::once(&*SPEAKER_MODEL))
+        .find(|m| m.id() == id)
+}
+
+/// x-vector model used for speaker identification, installed next to the
+/// speech models but never offered as one
+static SPEAKER_MODEL: Lazy<ModelInfo> = Lazy::new(|| ModelInfo {
+    name: "Speaker Identification".to_string(),
+    url: "https://alphacephei.com/vosk/models/vosk-model-spk-0.4.zip".to_string(),
+    size: "13MB",
+    description: "Speaker identification model for all languages",
+    language: "Any",
+});
+
+const MODELS_BASE_URL: &str = "https://alphacephei.com/vosk/models";
+
+static AVAILABLE_MODELS: Lazy<Vec<ModelInfo>> = Lazy::new(|| vec![
+    // English Models
+    ModelInfo {
         name: "Small English US".to_string(),
         url: "https://alphacephei.com/vosk/models/vosk-model-small-en-us-0.15.zip".to_string(),
         size: "40MB",
@@ -479,51 +1986,185 @@ static AVAILABLE_MODELS: Lazy<Vec<ModelInfo>> = Lazy::new(|| vec![
     },
 ]);
 
-async fn download_and_extract_model(model: &ModelInfo, dest_dir: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
-    let zip_path = dest_dir.join("model.zip");
+/// How often progress is printed when stderr is not a terminal
+const PLAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Byte progress for downloads and extraction. Draws an indicatif bar on a
+/// terminal (a spinner when the total is unknown) and falls back to periodic
+/// plain-text lines otherwise, so logs from CI or systemd stay readable.
+struct TransferProgress {
+    label: &'static str,
+    bar: Option<ProgressBar>,
+    total: Option<u64>,
+    current: u64,
+    last_report: Instant,
+}
+
+impl TransferProgress {
+    fn new(label: &'static str, total: Option<u64>) -> Result<Self, Box<dyn std::error::Error>> {
+        let bar = if std::io::stderr().is_terminal() {
+            let bar = match total {
+                Some(total) => {
+                    let pb = ProgressBar::new(total);
+                    pb.set_style(ProgressStyle::default_bar()
+                        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
+                        .progress_chars("##-"));
+                    pb
+                }
+                None => {
+                    let pb = ProgressBar::new_spinner();
+                    pb.set_style(ProgressStyle::default_spinner()
+                        .template("{spinner:.green} [{elapsed_precise}] {bytes} ({bytes_per_sec})")?);
+                    pb.enable_steady_tick(Duration::from_millis(100));
+                    pb
+                }
+            };
+            Some(bar)
+        } else {
+            None
+        };
+        
+        Ok(TransferProgress {
+            label,
+            bar,
+            total,
+            current: 0,
+            last_report: Instant::now(),
+        })
+    }
     
-    println!("📥 Downloading {} ({})...", model.name, model.size);
+    fn inc(&mut self, delta: u64) {
+        self.current += delta;
+        match &self.bar {
+            Some(bar) => bar.inc(delta),
+            None => {
+                if self.last_report.elapsed() >= PLAIN_PROGRESS_INTERVAL {
+                    self.report();
+                    self.last_report = Instant::now();
+                }
+            }
+        }
+    }
+    
+    fn report(&self) {
+        match self.total {
+            Some(total) if total > 0 => println!(
+                "{}: {} / {} ({}%)",
+                self.label,
+                HumanBytes(self.current),
+                HumanBytes(total),
+                self.current * 100 / total,
+            ),
+            _ => println!("{}: {}", self.label, HumanBytes(self.current)),
+        }
+    }
     
-    let client = Client::new();
-    let response = client.get(&model.url).send().await?.error_for_status()?;
+    fn finish(self) {
+        match &self.bar {
+            Some(bar) => bar.finish(),
+            None => self.report(),
+        }
+    }
+}
 
-    let total_size = response.content_length().ok_or("Failed to get content length")?;
-    let mut file = File::create(&zip_path)?;
-    let mut stream = response.bytes_stream();
+async fn download_file(client: &Client, url: &str, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let response = client.get(url).send().await?.error_for_status()?;
 
-    let pb = ProgressBar::new(total_size);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
-        .progress_chars("##-"));
+    // Mirrors using chunked transfer encoding don't send a Content-Length
+    let mut progress = TransferProgress::new("Downloading", response.content_length())?;
+    let mut file = File::create(path)?;
+    let mut stream = response.bytes_stream();
 
     while let Some(chunk) = stream.next().await {
         let chunk = chunk?;
         file.write_all(&chunk)?;
-        pb.inc(chunk.len() as u64);
+        progress.inc(chunk.len() as u64);
     }
 
-    pb.finish_with_message("Download complete");
+    progress.finish();
     
-    // Extract the zip file
-    println!("📦 Extracting model...");
-    let file = File::open(&zip_path)?;
+    Ok(())
+}
+
+/// Extract `zip_path` into `dest_dir`, reporting progress in uncompressed
+/// bytes. Entries whose path would leave `dest_dir` are skipped.
+fn extract_archive(zip_path: &Path, dest_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let file = File::open(zip_path)?;
     let mut archive = ZipArchive::new(file)?;
     
+    let mut total_size = 0;
+    for i in 0..archive.len() {
+        total_size += archive.by_index(i)?.size();
+    }
+    
+    let mut progress = TransferProgress::new("Extracting", Some(total_size))?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    
     for i in 0..archive.len() {
         let mut file = archive.by_index(i)?;
-        let outpath = dest_dir.join(file.name());
+        let Some(name) = file.enclosed_name() else {
+            warn!("Skipping archive entry with an unsafe path: {}", file.name());
+            progress.inc(file.size());
+            continue;
+        };
+        let outpath = dest_dir.join(name);
         
-        if file.name().ends_with('/') {
+        if file.is_dir() {
             create_dir_all(&outpath)?;
         } else {
             if let Some(p) = outpath.parent() {
                 create_dir_all(p)?;
             }
             let mut outfile = File::create(&outpath)?;
-            std::io::copy(&mut file, &mut outfile)?;
+            loop {
+                let read = file.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                outfile.write_all(&buffer[..read])?;
+                progress.inc(read as u64);
+            }
+        }
+    }
+    
+    progress.finish();
+    
+    Ok(())
+}
+
+async fn download_and_extract_model(model: &ModelInfo, dest_dir: &Path, download: &DownloadConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let zip_path = dest_dir.join("model.zip");
+    let url = download.resolve_url(&model.url);
+    let client = download.build_client()?;
+    
+    println!("📥 Downloading {} ({})...", model.name, model.size);
+    if url != model.url {
+        info!("Downloading from mirror: {}", url);
+    }
+    
+    let mut attempt = 0;
+    loop {
+        let error = match download_file(&client, &url, &zip_path).await {
+            Ok(()) => break,
+            Err(e) => e.to_string(),
+        };
+        
+        if attempt >= download.retries {
+            return Err(format!("Download failed after {} attempts: {}", attempt + 1, error).into());
         }
+        
+        let delay = Duration::from_millis(download.retry_backoff_ms.saturating_mul(1 << attempt.min(16)));
+        attempt += 1;
+        warn!("Download failed: {}. Retrying in {:.1}s ({}/{})", error, delay.as_secs_f64(), attempt, download.retries);
+        tokio::time::sleep(delay).await;
     }
     
+    // Extract the zip file
+    println!("📦 Extracting model...");
+    let (archive, dir) = (zip_path.clone(), dest_dir.to_path_buf());
+    tokio::task::spawn_blocking(move || extract_archive(&archive, &dir).map_err(|e| e.to_string()))
+        .await??;
+    
     // Clean up zip file
     std::fs::remove_file(&zip_path)?;
     
@@ -532,34 +2173,253 @@ async fn download_and_extract_model(model: &ModelInfo, dest_dir: &std::path::Pat
     Ok(())
 }
 
-fn select_model() -> Result<ModelInfo, Box<dyn std::error::Error>> {
+/// Id of the model offered as the default choice
+const RECOMMENDED_MODEL_ID: &str = "vosk-model-en-us-0.22-lgraph";
+
+fn recommended_model() -> &'static ModelInfo {
+    find_model_info(RECOMMENDED_MODEL_ID).expect("recommended model is in AVAILABLE_MODELS")
+}
+
+/// Language code of the system locale, mapped to the codes used in model ids
+fn system_language() -> Option<(String, Option<String>)> {
+    let locale = sys_locale::get_locale()?.to_lowercase();
+    let mut parts = locale.split(['-', '_', '.']);
+    let language = match parts.next()? {
+        "zh" => "cn",
+        "vi" => "vn",
+        other => other,
+    };
+    Some((language.to_string(), parts.next().map(str::to_string)))
+}
+
+fn select_model(manifest: &ModelManifest, models_dir: &Path) -> Result<ModelInfo, Box<dyn std::error::Error>> {
     println!("🎙️  Welcome to Scriba!");
     println!("Please select a speech recognition model:");
+    
+    let free_space = fs2::available_space(models_dir).ok();
+    if let Some(free_space) = free_space {
+        println!("Free disk space: {}", HumanBytes(free_space));
+    }
     println!();
     
-    let items: Vec<String> = AVAILABLE_MODELS.iter()
-        .map(|m| format!("{} ({}) - {} ({})", m.name, m.language, m.description, m.size))
+    let is_installed = |model: &ModelInfo| manifest.models.contains_key(model.id());
+    
+    // First pick a language, defaulting to the system locale
+    let mut languages: Vec<&str> = Vec::new();
+    for model in AVAILABLE_MODELS.iter() {
+        if !languages.contains(&model.language) {
+            languages.push(model.language);
+        }
+    }
+    
+    let language_items: Vec<String> = languages.iter()
+        .map(|&language| {
+            let models: Vec<&ModelInfo> = AVAILABLE_MODELS.iter().filter(|m| m.language == language).collect();
+            let installed = models.iter().filter(|m| is_installed(m)).count();
+            if installed > 0 {
+                format!("{} ({} models, {} installed)", language, models.len(), installed)
+            } else {
+                format!("{} ({} models)", language, models.len())
+            }
+        })
+        .collect();
+    
+    let locale_model = system_language().and_then(|(language, region)| {
+        let matching = AVAILABLE_MODELS.iter().filter(|m| m.language_code().0 == language);
+        let mut fallback = None;
+        for model in matching {
+            if region.is_some() && model.language_code().1 == region.as_deref() {
+                return Some(model);
+            }
+            fallback.get_or_insert(model);
+        }
+        fallback
+    });
+    let default_language = locale_model.unwrap_or_else(recommended_model).language;
+    
+    let language_selection = Select::new()
+        .with_prompt("Choose a language")
+        .items(&language_items)
+        .default(languages.iter().position(|&l| l == default_language).unwrap_or(0))
+        .interact()?;
+    let language = languages[language_selection];
+    
+    // Then pick a model of that language, smallest first
+    let mut models: Vec<&ModelInfo> = AVAILABLE_MODELS.iter().filter(|m| m.language == language).collect();
+    models.sort_by_key(|m| m.size_bytes());
+    
+    let items: Vec<String> = models.iter()
+        .map(|m| {
+            let status = if is_installed(m) {
+                " ✓ installed"
+            } else if free_space.is_some_and(|free| free < m.size_bytes() * 2) {
+                // The archive and the extracted model exist side by side while installing
+                " ⚠ not enough free disk space"
+            } else {
+                ""
+            };
+            format!("{} - {} ({}){}", m.name, m.description, m.size, status)
+        })
         .collect();
     
+    let default = models.iter().position(|m| m.id() == RECOMMENDED_MODEL_ID)
+        .or_else(|| models.iter().position(|m| is_installed(m)))
+        .unwrap_or(0);
+    
     let selection = Select::new()
         .with_prompt("Choose a model")
         .items(&items)
-        .default(1) // Default to the English US Recommended model
+        .default(default)
         .interact()?;
     
-    Ok(AVAILABLE_MODELS[selection].clone())
+    Ok(models[selection].clone())
 }
 
-fn find_model_directory(models_dir: &std::path::Path) -> Option<std::path::PathBuf> {
-    if let Ok(entries) = std::fs::read_dir(models_dir) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if path.is_dir() && path.file_name().unwrap().to_string_lossy().starts_with("vosk-model") {
-                return Some(path);
+/// Pick a model to install: the recommended one with `--yes`, otherwise
+/// prompt, which is only possible when stdin is a terminal.
+fn choose_model_to_install(assume_yes: bool, manifest: &ModelManifest, models_dir: &Path) -> Result<ModelInfo, Box<dyn std::error::Error>> {
+    if assume_yes {
+        let model = recommended_model();
+        println!("Using the recommended model: {} [{}]", model.name, model.id());
+        return Ok(model.clone());
+    }
+    
+    if !std::io::stdin().is_terminal() {
+        return Err(format!(
+            "No model selected and stdin is not a terminal, so the model picker can't be shown. \
+             Pass --model <ID> (or set SCRIBA_MODEL or `model` in config.toml) to choose a model, \
+             or --yes to install the recommended one ({})",
+            RECOMMENDED_MODEL_ID,
+        ).into());
+    }
+    
+    select_model(manifest, models_dir)
+}
+
+fn find_model_directory(dir: &Path) -> Option<PathBuf> {
+    let mut candidates: Vec<PathBuf> = std::fs::read_dir(dir).ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir() && is_model_directory_name(path))
+        .collect();
+    
+    // `read_dir` order is filesystem-dependent, so sort to stay deterministic
+    candidates.sort();
+    candidates.into_iter().next()
+}
+
+fn is_model_directory_name(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().starts_with("vosk-model"))
+        .unwrap_or(false)
+}
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// A model found on disk, with its path relative to the models directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct InstalledModel {
+    name: String,
+    path: PathBuf,
+}
+
+/// Index of the installed models, keyed by `ModelInfo::id`, stored as
+/// `models/manifest.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ModelManifest {
+    last_used: Option<String>,
+    models: BTreeMap<String, InstalledModel>,
+}
+
+impl ModelManifest {
+    fn load(models_dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = models_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(ModelManifest::default());
+        }
+        
+        let contents = std::fs::read_to_string(&path)?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid model manifest {}: {}", path.display(), e).into())
+    }
+    
+    fn save(&self, models_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = serde_json::to_string_pretty(self)?;
+        std::fs::write(models_dir.join(MANIFEST_FILE), contents)?;
+        Ok(())
+    }
+    
+    /// Ids of the installed speech recognition models
+    fn speech_models(&self) -> impl Iterator<Item = &String> {
+        self.models.keys().filter(|id| id.as_str() != SPEAKER_MODEL.id())
+    }
+    
+    /// Rebuild the index from what is actually on disk. Handles both the
+    /// `models/<name>/vosk-model-*` layout used by downloads and model
+    /// directories placed directly in `models/`.
+    fn reindex(&mut self, models_dir: &Path) {
+        let mut entries: Vec<PathBuf> = match std::fs::read_dir(models_dir) {
+            Ok(entries) => entries.flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        entries.sort();
+        
+        let mut models = BTreeMap::new();
+        for entry in entries {
+            let (model_dir, info) = if is_model_directory_name(&entry) {
+                let dir_name = entry.file_name().unwrap().to_string_lossy().to_string();
+                (entry, find_any_model_info(&dir_name))
+            } else if let Some(model_dir) = find_model_directory(&entry) {
+                let parent_name = entry.file_name().unwrap().to_string_lossy().to_string();
+                let dir_name = model_dir.file_name().unwrap().to_string_lossy().to_string();
+                let info = AVAILABLE_MODELS.iter()
+                    .chain(std::iter::once(&*SPEAKER_MODEL))
+                    .find(|m| m.dir_name() == parent_name)
+                    .or_else(|| find_any_model_info(&dir_name));
+                (model_dir, info)
+            } else {
+                continue;
+            };
+            
+            let dir_name = model_dir.file_name().unwrap().to_string_lossy().to_string();
+            let (id, name) = match info {
+                Some(info) => (info.id().to_string(), info.name.clone()),
+                None => (dir_name.clone(), dir_name),
+            };
+            let path = model_dir.strip_prefix(models_dir).unwrap_or(&model_dir).to_path_buf();
+            
+            // Keep the first match so duplicates resolve the same way on every run
+            models.entry(id).or_insert(InstalledModel { name, path });
+        }
+        
+        self.models = models;
+        if let Some(last_used) = &self.last_used {
+            if !self.models.contains_key(last_used) {
+                self.last_used = None;
             }
         }
     }
-    None
+}
+
+/// Download `model` into its own directory and record it in the manifest
+async fn install_model(model: &ModelInfo, models_dir: &Path, manifest: &mut ModelManifest, download: &DownloadConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let model_specific_dir = models_dir.join(model.dir_name());
+    create_dir_all(&model_specific_dir)?;
+    download_and_extract_model(model, &model_specific_dir, download).await?;
+    
+    // Find the actual model directory inside the downloaded/extracted content
+    let model_dir = find_model_directory(&model_specific_dir)
+        .ok_or("Could not find extracted model directory")?;
+    let path = model_dir.strip_prefix(models_dir).unwrap_or(&model_dir).to_path_buf();
+    
+    manifest.models.insert(model.id().to_string(), InstalledModel {
+        name: model.name.clone(),
+        path,
+    });
+    manifest.save(models_dir)
 }
 
 // Enhanced number-to-digit conversion for software engineering contexts
@@ -586,40 +2446,6 @@ static NUMBER_PATTERNS: Lazy<Vec<(Regex, &'static str)>> = Lazy::new(|| vec![
     (Regex::new(r"\beight hundred\b").unwrap(), "800"),
     (Regex::new(r"\bnine hundred\b").unwrap(), "900"),
     
-    // Teens
-    (Regex::new(r"\beleven\b").unwrap(), "11"),
-    (Regex::new(r"\btwelve\b").unwrap(), "12"),
-    (Regex::new(r"\bthirteen\b").unwrap(), "13"),
-    (Regex::new(r"\bfourteen\b").unwrap(), "14"),
-    (Regex::new(r"\bfifteen\b").unwrap(), "15"),
-    (Regex::new(r"\bsixteen\b").unwrap(), "16"),
-    (Regex::new(r"\bseventeen\b").unwrap(), "17"),
-    (Regex::new(r"\beighteen\b").unwrap(), "18"),
-    (Regex::new(r"\bnineteen\b").unwrap(), "19"),
-    
-    // Tens
-    (Regex::new(r"\bten\b").unwrap(), "10"),
-    (Regex::new(r"\btwenty\b").unwrap(), "20"),
-    (Regex::new(r"\bthirty\b").unwrap(), "30"),
-    (Regex::new(r"\bforty\b").unwrap(), "40"),
-    (Regex::new(r"\bfifty\b").unwrap(), "50"),
-    (Regex::new(r"\bsixty\b").unwrap(), "60"),
-    (Regex::new(r"\bseventy\b").unwrap(), "70"),
-    (Regex::new(r"\beighty\b").unwrap(), "80"),
-    (Regex::new(r"\bninety\b").unwrap(), "90"),
-    
-    // Individual digits (after more complex patterns)
-    (Regex::new(r"\bzero\b").unwrap(), "0"),
-    (Regex::new(r"\bone\b").unwrap(), "1"),
-    (Regex::new(r"\btwo\b").unwrap(), "2"),
-    (Regex::new(r"\bthree\b").unwrap(), "3"),
-    (Regex::new(r"\bfour\b").unwrap(), "4"),
-    (Regex::new(r"\bfive\b").unwrap(), "5"),
-    (Regex::new(r"\bsix\b").unwrap(), "6"),
-    (Regex::new(r"\bseven\b").unwrap(), "7"),
-    (Regex::new(r"\beight\b").unwrap(), "8"),
-    (Regex::new(r"\bnine\b").unwrap(), "9"),
-    
     // Common programming terms
     (Regex::new(r"\bnull\b").unwrap(), "null"),
     (Regex::new(r"\btrue\b").unwrap(), "true"),
@@ -632,165 +2458,3174 @@ static NUMBER_PATTERNS: Lazy<Vec<(Regex, &'static str)>> = Lazy::new(|| vec![
     (Regex::new(r"\bopen brace\b").unwrap(), "{"),
     (Regex::new(r"\bclose brace\b").unwrap(), "}"),
     (Regex::new(r"\bsemicolon\b").unwrap(), ";"),
-    (Regex::new(r"\bcolon\b").unwrap(), ":"),
     (Regex::new(r"\bcomma\b").unwrap(), ","),
-    (Regex::new(r"\bdot\b").unwrap(), "."),
     (Regex::new(r"\bequals\b").unwrap(), "="),
-    (Regex::new(r"\bplus\b").unwrap(), "+"),
-    (Regex::new(r"\bminus\b").unwrap(), "-"),
-    (Regex::new(r"\btimes\b").unwrap(), "*"),
-    (Regex::new(r"\bdivide\b").unwrap(), "/"),
 ]);
 
-fn convert_words_to_numbers(text: &str) -> String {
+// Words that are also common English, only converted in context
+// (see `resolve_ambiguous_words`) or after "number"/"symbol". Numbers spoken
+// as several words ("twenty five") are always converted.
+const DIGIT_WORDS: &[(&str, &str)] = &[
+    ("zero", "0"), ("one", "1"), ("two", "2"), ("three", "3"), ("four", "4"),
+    ("five", "5"), ("six", "6"), ("seven", "7"), ("eight", "8"), ("nine", "9"),
+];
+const AMBIGUOUS_NUMBERS: &[(&str, &str)] = &[
+    ("ten", "10"), ("eleven", "11"), ("twelve", "12"), ("thirteen", "13"), ("fourteen", "14"),
+    ("fifteen", "15"), ("sixteen", "16"), ("seventeen", "17"), ("eighteen", "18"), ("nineteen", "19"),
+    ("twenty", "20"), ("thirty", "30"), ("forty", "40"), ("fifty", "50"), ("sixty", "60"),
+    ("seventy", "70"), ("eighty", "80"), ("ninety", "90"), ("hundred", "100"), ("thousand", "1000"),
+];
+const AMBIGUOUS_OPERATORS: &[(&str, &str)] = &[
+    ("plus", "+"), ("minus", "-"), ("times", "*"), ("divide", "/"), ("dot", "."), ("colon", ":"),
+];
+
+const NATO_ALPHABET: &[&str] = &[
+    "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel", "india",
+    "juliet", "kilo", "lima", "mike", "november", "oscar", "papa", "quebec", "romeo",
+    "sierra", "tango", "uniform", "victor", "whiskey", "x-ray", "yankee", "zulu",
+];
+
+// Escaped words are swapped for characters from this private use plane
+// while the rest of the text is processed
+const PLACEHOLDER_BASE: u32 = 0xF0000;
+
+fn is_placeholder(c: char) -> bool {
+    (PLACEHOLDER_BASE..PLACEHOLDER_BASE + 0xFFFE).contains(&(c as u32))
+}
+
+/// Letter spelled by a word in spell mode: a single letter, a NATO
+/// alphabet word or a digit word
+fn spelled_char(word: &str) -> Option<char> {
+    let mut chars = word.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return c.is_alphanumeric().then_some(c);
+    }
+    if let Some(i) = NATO_ALPHABET.iter().position(|w| *w == word) {
+        return Some((b'a' + i as u8) as char);
+    }
+    lookup(DIGIT_WORDS, word).and_then(|d| d.chars().next())
+}
+
+/// Take escaped words out of `text` before post-processing, replacing each
+/// with a placeholder:
+/// - "literal <word>" keeps the word as spoken ("literal comma" is `comma`)
+/// - "spell <letters>" joins letters, NATO alphabet or digit words into one
+///   word, "capital" upper-casing the next letter ("spell capital x ray
+///   alpha one" is `Xa1`)
+///
+/// Returns the text and the escaped words, restored by `restore_escapes`.
+fn protect_escapes(text: &str) -> (String, Vec<String>) {
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let mut escaped = Vec::new();
+    let mut output: Vec<String> = Vec::with_capacity(tokens.len());
+    let placeholder = |i: usize| char::from_u32(PLACEHOLDER_BASE + i as u32).unwrap_or(' ').to_string();
+    
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i] {
+            "literal" if i + 1 < tokens.len() => {
+                output.push(placeholder(escaped.len()));
+                escaped.push(tokens[i + 1].to_string());
+                i += 2;
+            }
+            "spell" => {
+                let mut word = String::new();
+                let mut j = i + 1;
+                while j < tokens.len() {
+                    let capital = tokens[j] == "capital" && j + 1 < tokens.len();
+                    let letter = if capital { tokens[j + 1] } else { tokens[j] };
+                    // "x ray" is how the recognizer usually writes x-ray
+                    let (letter, len) = if letter == "x" && tokens.get(j + 1 + capital as usize) == Some(&"ray") {
+                        ("x-ray", 2)
+                    } else {
+                        (letter, 1)
+                    };
+                    let Some(c) = spelled_char(letter) else {
+                        break;
+                    };
+                    if capital {
+                        word.extend(c.to_uppercase());
+                    } else {
+                        word.push(c);
+                    }
+                    j += capital as usize + len;
+                }
+                
+                if word.is_empty() {
+                    output.push(tokens[i].to_string());
+                    i += 1;
+                } else {
+                    output.push(placeholder(escaped.len()));
+                    escaped.push(word);
+                    i = j;
+                }
+            }
+            token => {
+                output.push(token.to_string());
+                i += 1;
+            }
+        }
+    }
+    
+    (output.join(" "), escaped)
+}
+
+fn restore_escapes(text: &str, escaped: &[String]) -> String {
+    let mut restored = String::with_capacity(text.len());
+    for c in text.chars() {
+        let word = is_placeholder(c)
+            .then(|| escaped.get((c as u32 - PLACEHOLDER_BASE) as usize))
+            .flatten();
+        match word {
+            Some(word) => restored.push_str(word),
+            None => restored.push(c),
+        }
+    }
+    restored
+}
+
+// Where a snippet leaves the cursor; removed by `TextTyper`, which moves
+// the cursor back there after typing
+const CURSOR_MARKER: char = '\u{E002}';
+
+// Stand-ins for quotes until spacing is fixed, since `"` alone doesn't
+// tell whether it opens or closes a quotation
+const OPEN_QUOTE: char = '\u{E000}';
+const CLOSE_QUOTE: char = '\u{E001}';
+
+// Spoken punctuation for prose mode
+static SPOKEN_PUNCTUATION: Lazy<Vec<(Regex, String)>> = Lazy::new(|| vec![
+    (Regex::new(r"\bnew paragraph\b").unwrap(), "\n\n".to_string()),
+    (Regex::new(r"\bnew line\b").unwrap(), "\n".to_string()),
+    (Regex::new(r"\b(period|full stop)\b").unwrap(), ".".to_string()),
+    (Regex::new(r"\bquestion mark\b").unwrap(), "?".to_string()),
+    (Regex::new(r"\bexclamation (mark|point)\b").unwrap(), "!".to_string()),
+    (Regex::new(r"\bcomma\b").unwrap(), ",".to_string()),
+    (Regex::new(r"\bsemicolon\b").unwrap(), ";".to_string()),
+    (Regex::new(r"\bcolon\b").unwrap(), ":".to_string()),
+    (Regex::new(r"\bellipsis\b").unwrap(), "...".to_string()),
+    (Regex::new(r"\bopen paren(thesis)?\b").unwrap(), "(".to_string()),
+    (Regex::new(r"\bclose paren(thesis)?\b").unwrap(), ")".to_string()),
+    // Before the plain "quote", which would otherwise turn "close quote" into `close "`
+    (Regex::new(r"\b(close|end) quote\b").unwrap(), CLOSE_QUOTE.to_string()),
+    (Regex::new(r"\b(open )?quote\b").unwrap(), OPEN_QUOTE.to_string()),
+]);
+
+static SPACE_BEFORE_PUNCTUATION: Lazy<Regex> = Lazy::new(|| Regex::new(r"[ \t]+([,.?!:;)\u{E001}])").unwrap());
+static SPACE_AFTER_OPENING: Lazy<Regex> = Lazy::new(|| Regex::new(r"([(\u{E000}])[ \t]+").unwrap());
+static SPACE_AROUND_NEWLINE: Lazy<Regex> = Lazy::new(|| Regex::new(r"[ \t]*\n[ \t]*").unwrap());
+static REPEATED_SPACES: Lazy<Regex> = Lazy::new(|| Regex::new(r"[ \t]{2,}").unwrap());
+static PRONOUN_I: Lazy<Regex> = Lazy::new(|| Regex::new(r"\bi\b").unwrap());
+
+fn convert_words_to_numbers(text: &str, threshold: f64) -> String {
     // Use text2num library for comprehensive number conversion
     let en = Language::english();
     // The function directly returns a String, not a Result
-    replace_numbers_in_text(text, &en, 0.0)
+    replace_numbers_in_text(text, &en, threshold)
 }
 
-fn enhance_transcription(text: &str) -> String {
-    let mut result = text.to_lowercase();
+/// Format `text` as prose: spoken punctuation, punctuation spacing and
+/// sentence capitalization. `sentence_start` tells whether the text begins
+/// a new sentence.
+fn format_prose(text: &str, sentence_start: bool) -> String {
+    // Keep small isolated numbers as words ("this one works")
+    let mut result = convert_words_to_numbers(text, 10.0);
     
-    // First, handle complex number conversions
-    result = convert_words_to_numbers(&result);
+    for (pattern, replacement) in SPOKEN_PUNCTUATION.iter() {
+        result = pattern.replace_all(&result, replacement.as_str()).to_string();
+    }
     
-    // Then apply simple pattern replacements
-    for (pattern, replacement) in NUMBER_PATTERNS.iter() {
-        result = pattern.replace_all(&result, *replacement).to_string();
+    result = SPACE_BEFORE_PUNCTUATION.replace_all(&result, "$1").to_string();
+    result = SPACE_AFTER_OPENING.replace_all(&result, "$1").to_string();
+    result = SPACE_AROUND_NEWLINE.replace_all(&result, "\n").to_string();
+    result = REPEATED_SPACES.replace_all(&result, " ").to_string();
+    result = PRONOUN_I.replace_all(&result, "I").to_string();
+    result = result.trim_matches(|c| c == ' ' || c == '\t').to_string();
+    
+    // Capitalize the first letter of every sentence
+    let mut capitalize = sentence_start;
+    let mut formatted = String::with_capacity(result.len());
+    for c in result.chars() {
+        match c {
+            OPEN_QUOTE | CLOSE_QUOTE => formatted.push('"'),
+            // Escaped words are typed as spoken, even at the start of a sentence
+            c if is_placeholder(c) => {
+                formatted.push(c);
+                capitalize = false;
+            }
+            c if capitalize && c.is_alphabetic() => {
+                formatted.extend(c.to_uppercase());
+                capitalize = false;
+            }
+            _ => formatted.push(c),
+        }
+        if matches!(c, '.' | '?' | '!' | '\n') {
+            capitalize = true;
+        }
     }
     
-    result
+    formatted
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Cli::parse();
-    
-    // Setup logging
-    let log_level = if args.debug { "debug" } else { "info" };
-    tracing_subscriber::fmt()
-        .with_env_filter(format!("scriba={}", log_level))
-        .init();
-    
-    // Configuration directory in the user's home
-    let config_path = config_dir()
-        .ok_or("Could not find config directory")?
-        .join("scriba");
-    create_dir_all(&config_path)?;
+fn is_operator(token: &str) -> bool {
+    !token.is_empty() && token.chars().all(|c| "=+-*/%<>!&|".contains(c))
+}
 
-    let models_dir = config_path.join("models");
-    create_dir_all(&models_dir)?;
+fn is_word(token: &str) -> bool {
+    !token.is_empty() && !is_operator(token) && !matches!(token, "(" | ")" | "[" | "]" | "{" | "}" | ";" | "," | "." | ":" | "::" | "?" | "@")
+}
 
-    // Check for existing model or prompt for selection
-    let model_dir = if args.select_model || find_model_directory(&models_dir).is_none() {
-        let selected_model = select_model()?;
-        
-        let model_specific_dir = models_dir.join(&selected_model.name.replace(" ", "_").to_lowercase());
-        
-        if !model_specific_dir.exists() || args.select_model {
-            create_dir_all(&model_specific_dir)?;
-            download_and_extract_model(&selected_model, &model_specific_dir).await?;
+/// Words that start a member access in most languages
+const CODE_RECEIVERS: &[&str] = &["self", "this", "super", "crate", "cls", "std"];
+
+/// Whether `token` is clearly code rather than an ordinary word: an escaped
+/// or vocabulary term, a word with an underscore or digits, or a receiver
+/// such as "self"
+fn is_identifier(token: &str) -> bool {
+    token.chars().any(is_placeholder)
+        || (token.contains('_') && is_word(token))
+        || (token.chars().any(|c| c.is_ascii_digit()) && token.chars().any(|c| c.is_alphabetic()))
+        || CODE_RECEIVERS.contains(&token)
+}
+
+/// A language profile built into scriba, extended by `[profiles.<name>]`
+struct BuiltinProfile {
+    name: &'static str,
+    symbols: &'static [(&'static str, &'static str)],
+    keywords: &'static [&'static str],
+    identifier_case: IdentifierCase,
+    /// Spoken form → template, see `Snippet`
+    snippets: &'static [(&'static str, &'static str)],
+}
+
+const BUILTIN_PROFILES: &[BuiltinProfile] = &[
+    BuiltinProfile {
+        name: "generic",
+        symbols: &[],
+        keywords: &[],
+        identifier_case: IdentifierCase::Snake,
+        snippets: &[],
+    },
+    BuiltinProfile {
+        name: "rust",
+        symbols: &[
+            ("ampersand mut", "&mut"), ("ampersand", "&"), ("fat arrow", "=>"), ("arrow", "->"),
+            ("double colon", "::"), ("question mark", "?"), ("not equals", "!="), ("bang", "!"),
+            ("pipe", "|"),
+        ],
+        // Type and variant names such as `Result` or `Ok` double as variable
+        // names ("let result equals ok"), so none are cased by default
+        keywords: &[],
+        identifier_case: IdentifierCase::Snake,
+        snippets: &[
+            ("function named {name} taking {params}", "fn {name}({params})"),
+            ("function named {name}", "fn {name}()"),
+            ("struct named {name}", "struct {name:pascal}"),
+            ("let mutable {name}", "let mut {name}"),
+        ],
+    },
+    BuiltinProfile {
+        name: "python",
+        symbols: &[
+            ("arrow", "->"), ("double star", "**"), ("not equals", "!="), ("at sign", "@"),
+        ],
+        keywords: &["True", "False", "None"],
+        identifier_case: IdentifierCase::Snake,
+        snippets: &[
+            ("function named {name} taking {params}", "def {name}({params}):"),
+            ("function named {name}", "def {name}():"),
+            ("class named {name}", "class {name:pascal}:"),
+        ],
+    },
+    BuiltinProfile {
+        name: "javascript",
+        symbols: &[
+            ("fat arrow", "=>"), ("triple equals", "==="), ("strict equals", "==="),
+            ("not equals", "!=="), ("double ampersand", "&&"), ("double pipe", "||"),
+            ("question mark", "?"),
+        ],
+        keywords: &[],
+        identifier_case: IdentifierCase::Camel,
+        snippets: &[
+            ("function named {name} taking {params}", "function {name}({params})"),
+            ("function named {name}", "function {name}()"),
+            ("arrow function taking {params}", "({params}) =>"),
+            ("arrow function", "() =>"),
+            ("class named {name}", "class {name:pascal}"),
+        ],
+    },
+];
+
+static SNIPPET_PLACEHOLDER: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{(\w+)(?::(\w+))?\}").unwrap());
+static LIST_SEPARATOR: Lazy<Regex> = Lazy::new(|| Regex::new(r"\s*(?:,|\band\b|\bcomma\b)\s*").unwrap());
+
+/// A spoken form expanded to a code template when it is the whole
+/// utterance. `{name}` placeholders in the spoken form capture words that
+/// fill the placeholders of the same name in the template, as identifiers
+/// in the profile casing (`{name:pascal}` forces one, `{name:raw}` keeps
+/// the words as spoken) and as a comma-separated list when several are
+/// given ("x and y" is `x, y`).
+///
+/// The cursor is left at `$0` in the template or, without one, at the
+/// first placeholder the trigger didn't fill.
+struct Snippet {
+    pattern: Regex,
+    template: String,
+}
+
+impl Snippet {
+    fn new(spoken: &str, template: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let spoken = spoken.to_lowercase();
+        let mut pattern = String::from("^");
+        let mut last = 0;
+        for caps in SNIPPET_PLACEHOLDER.captures_iter(&spoken) {
+            let placeholder = caps.get(0).unwrap();
+            pattern.push_str(&regex::escape(&spoken[last..placeholder.start()]));
+            pattern.push_str(&format!("(?P<{}>.+?)", &caps[1]));
+            last = placeholder.end();
         }
+        pattern.push_str(&regex::escape(&spoken[last..]));
+        pattern.push('$');
         
-        // Find the actual model directory inside the downloaded/extracted content
-        find_model_directory(&model_specific_dir)
-            .ok_or("Could not find extracted model directory")?
-    } else {
-        find_model_directory(&models_dir)
-            .ok_or("Could not find existing model directory")?
-    };
+        Ok(Snippet {
+            pattern: Regex::new(&pattern).map_err(|e| format!("Invalid snippet '{}': {}", spoken, e))?,
+            template: template.to_string(),
+        })
+    }
+    
+    fn expand(&self, text: &str, case: IdentifierCase) -> Option<String> {
+        let caps = self.pattern.captures(text)?;
+        let mut cursor_placed = self.template.contains("$0");
+        let expanded = SNIPPET_PLACEHOLDER.replace_all(&self.template, |placeholder: &regex::Captures| {
+            let Some(words) = caps.name(&placeholder[1]) else {
+                if cursor_placed {
+                    return String::new();
+                }
+                cursor_placed = true;
+                return CURSOR_MARKER.to_string();
+            };
+            
+            let case = match placeholder.get(2).map(|m| m.as_str()) {
+                Some("raw") => return words.as_str().to_string(),
+                Some("snake") => IdentifierCase::Snake,
+                Some("camel") => IdentifierCase::Camel,
+                Some("pascal") => IdentifierCase::Pascal,
+                _ => case,
+            };
+            LIST_SEPARATOR
+                .split(words.as_str())
+                .filter(|item| !item.is_empty())
+                .map(|item| case.apply(item))
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
+        Some(expanded.replacen("$0", &CURSOR_MARKER.to_string(), 1))
+    }
+}
 
-    info!("Starting Scriba...");
-    info!("Using model: {}", model_dir.display());
-    info!("Sample rate: {}", args.sample_rate);
-    info!("Confidence threshold: {}", args.confidence_threshold);
+/// Vocabulary, casing and snippets used in code mode for one language
+struct LanguageProfile {
+    name: String,
+    spacing: SpacingRules,
+    /// Applied before `NUMBER_PATTERNS`, longest phrase first
+    symbols: Vec<(Regex, String)>,
+    keywords: Vec<String>,
+    identifier_case: IdentifierCase,
+    snippets: Vec<Snippet>,
+}
 
-    // Load Vosk model
-    let model = Model::new(model_dir.to_str().ok_or("Invalid model path")?)
-        .ok_or("Failed to load model. Make sure the model exists at the specified path.")?;
-    
-    // Create audio processing channel
-    let (audio_tx, mut audio_rx) = mpsc::unbounded_channel::<Vec<f32>>();
-    
-    // Setup audio stream
-    setup_audio_stream(args.sample_rate, audio_tx)?;
-    
-    // Create audio processor
-    let (result_tx, mut result_rx) = mpsc::unbounded_channel::<TranscriptionResult>();
-    let processor = AudioProcessor::new(&model, args.sample_rate as f32)?;
-    
-    // Spawn audio processing task
-    let processor_handle = tokio::spawn(async move {
-        let mut buffer = Vec::new();
-        const BUFFER_SIZE: usize = 4000; // Process audio in chunks
+impl LanguageProfile {
+    fn new(
+        name: &str,
+        builtin: Option<&BuiltinProfile>,
+        config: Option<&ProfileConfig>,
+        global_snippets: &[SnippetConfig],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut symbols: BTreeMap<String, String> = builtin
+            .map(|b| b.symbols.iter().map(|(spoken, text)| (spoken.to_string(), text.to_string())).collect())
+            .unwrap_or_default();
+        let mut keywords: Vec<String> = builtin
+            .map(|b| b.keywords.iter().map(|k| k.to_string()).collect())
+            .unwrap_or_default();
+        // The first matching snippet wins: the profile's, then global, then built-in ones
+        let mut snippets = Vec::new();
+        let configured = config.map(|c| c.snippets.as_slice()).unwrap_or_default().iter().chain(global_snippets);
+        for snippet in configured {
+            snippets.push(Snippet::new(&snippet.trigger, &snippet.template)?);
+        }
+        for (spoken, template) in builtin.map(|b| b.snippets).unwrap_or_default() {
+            snippets.push(Snippet::new(spoken, template)?);
+        }
+        let mut spacing = SpacingRules::default();
+        let mut identifier_case = builtin.map(|b| b.identifier_case).unwrap_or(IdentifierCase::Snake);
         
-        while let Some(audio_data) = audio_rx.recv().await {
-            buffer.extend_from_slice(&audio_data);
-            
-            if buffer.len() >= BUFFER_SIZE {
-                let chunk: Vec<f32> = buffer.drain(..BUFFER_SIZE).collect();
-                let i16_chunk = convert_f32_to_i16(&chunk);
-                
-                match processor.process_audio(&i16_chunk) {
-                    Ok(Some(result)) => {
-                        if let Err(e) = result_tx.send(result) {
-                            error!("Failed to send transcription result: {}", e);
-                            break;
-                        }
-                    }
-                    Ok(None) => {}, // No transcription result
-                    Err(e) => {
-                        error!("Audio processing error: {}", e);
-                    }
-                }
+        if let Some(config) = config {
+            symbols.extend(config.symbols.iter().map(|(spoken, text)| (spoken.to_lowercase(), text.clone())));
+            keywords.extend(config.keywords.iter().cloned());
+            if let Some(rules) = &config.spacing {
+                spacing = rules.clone();
+            }
+            if let Some(case) = config.identifier_case {
+                identifier_case = case;
             }
         }
-    });
+        
+        let mut symbols: Vec<(String, String)> = symbols.into_iter().collect();
+        symbols.sort_by_key(|(spoken, _)| std::cmp::Reverse(spoken.len()));
+        let symbols = symbols
+            .into_iter()
+            .map(|(spoken, text)| {
+                let pattern = Regex::new(&format!(r"\b{}\b", regex::escape(&spoken)))?;
+                // Spaced so that `format_code` sees the symbol as a token
+                Ok((pattern, format!(" {} ", text)))
+            })
+            .collect::<Result<_, regex::Error>>()?;
+        
+        Ok(LanguageProfile {
+            name: name.to_string(),
+            spacing,
+            symbols,
+            keywords,
+            identifier_case,
+            snippets,
+        })
+    }
     
-    // Create text typer
-    let mut typer = if args.no_typing {
-        None
-    } else {
-        match TextTyper::new() {
-            Ok(t) => Some(t),
-            Err(e) => {
-                error!("Failed to create text typer: {}. Running in no-typing mode.", e);
-                None
+    fn expand_snippet(&self, text: &str) -> Option<String> {
+        self.snippets.iter().find_map(|snippet| snippet.expand(text, self.identifier_case))
+    }
+    
+    fn apply_keyword_casing(&self, text: &str) -> String {
+        text.split(' ')
+            .map(|word| {
+                self.keywords
+                    .iter()
+                    .find(|k| k.eq_ignore_ascii_case(word))
+                    .map_or(word, |k| k.as_str())
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+static PROFILE_SWITCH_COMMAND: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(?:switch to|use) (.+) profile$").unwrap());
+
+/// Every available language profile and the one in use
+struct Profiles {
+    profiles: Vec<LanguageProfile>,
+    active: usize,
+}
+
+impl Profiles {
+    fn load(config: &Config, selected: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut profiles = Vec::new();
+        for builtin in BUILTIN_PROFILES {
+            profiles.push(LanguageProfile::new(builtin.name, Some(builtin), config.profiles.get(builtin.name), &config.snippets)?);
+        }
+        let mut custom: Vec<_> = config.profiles.iter()
+            .filter(|(name, _)| !BUILTIN_PROFILES.iter().any(|b| b.name == name.as_str()))
+            .collect();
+        custom.sort_by_key(|(name, _)| name.as_str());
+        for (name, profile) in custom {
+            profiles.push(LanguageProfile::new(name, None, Some(profile), &config.snippets)?);
+        }
+        
+        let mut profiles = Profiles { profiles, active: 0 };
+        if let Some(name) = selected.or(config.profile.as_deref()) {
+            if !profiles.select(name) {
+                return Err(format!("Unknown profile '{}'. Available profiles: {}", name, profiles.names().join(", ")).into());
             }
         }
-    };
+        Ok(profiles)
+    }
     
-    println!("🎙️  Scriba is running!");
-    if typer.is_some() {
-        println!("📝 Text will be typed in the currently focused input field.");
-        println!("🛑 Press Ctrl+C to stop.");
-    } else {
-        println!("📄 Typing is disabled. Transcriptions will only be printed.");
-        println!("🛑 Press Ctrl+C to stop.");
+    fn names(&self) -> Vec<&str> {
+        self.profiles.iter().map(|p| p.name.as_str()).collect()
     }
-    println!();
     
-    // Process transcription results
-    while let Some(result) = result_rx.recv().await {
-        if result.is_final && result.confidence >= args.confidence_threshold {
-            let enhanced_text = enhance_transcription(&result.text);
-            
-            info!("📝 Transcription (confidence: {:.2}): {}", result.confidence, enhanced_text);
-            
-            if let Some(ref mut typer) = typer {
-                typer.type_text(&TranscriptionResult {
-                    text: enhanced_text,
-                    confidence: result.confidence,
-                    is_final: result.is_final,
-                }, args.confidence_threshold);
+    fn active(&self) -> &LanguageProfile {
+        &self.profiles[self.active]
+    }
+    
+    /// Find a profile by name, ignoring case and spaces since the recognizer
+    /// hears "javascript" as "java script"
+    fn position(&self, name: &str) -> Option<usize> {
+        let normalize = |s: &str| s.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_lowercase();
+        let name = normalize(name);
+        self.profiles.iter().position(|p| normalize(&p.name) == name)
+    }
+    
+    fn get(&self, name: &str) -> Option<&LanguageProfile> {
+        self.position(name).map(|index| &self.profiles[index])
+    }
+    
+    /// Make `name` the active profile
+    fn select(&mut self, name: &str) -> bool {
+        match self.position(name) {
+            Some(index) => {
+                self.active = index;
+                true
             }
-        } else if args.debug && !result.is_final {
-            info!("🔄 Partial: {}", result.text);
+            None => false,
         }
     }
-
-    processor_handle.await?;
     
-    Ok(())
+    /// Handle a "switch to <name> profile" or "use <name> profile" voice
+    /// command, returning whether `text` was one
+    fn handle_voice_command(&mut self, text: &str) -> bool {
+        let Some(caps) = PROFILE_SWITCH_COMMAND.captures(text.trim()) else {
+            return false;
+        };
+        self.select(&caps[1])
+    }
+}
+
+const VOCABULARY_FILE: &str = "vocabulary.txt";
+// Vocabulary files written by `scriba learn`
+const VOCABULARY_DIR: &str = "vocabulary";
+/// First line of the files written by `scriba learn`. Their entries are
+/// only applied in code mode, where identifiers are expected.
+const LEARNED_VOCABULARY_HEADER: &str = "# scriba: learned vocabulary";
+
+/// A known term, typed as `written` when something close to `spoken` is heard
+struct VocabularyEntry {
+    spoken: String,
+    written: String,
+    phonetic: String,
+    /// Number of words in `spoken`
+    words: usize,
+    /// Whether the entry comes from a file written by `scriba learn`
+    learned: bool,
+}
+
+/// Project-specific terms (`vocabulary.txt` and the files in `vocabulary/`
+/// in the config directory, and the files listed in `[vocabulary]`), one
+/// per line: either the term
+/// itself (`tokio`) or a spoken form and the term (`cube control = kubectl`).
+/// Lines starting with `#` are comments.
+struct Vocabulary {
+    entries: Vec<VocabularyEntry>,
+    threshold: f64,
+    /// Longest window of words compared to the entries
+    max_words: usize,
+}
+
+impl Vocabulary {
+    fn load(config_path: &Path, config: &VocabularyConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut vocabulary = Vocabulary { entries: Vec::new(), threshold: config.threshold, max_words: 0 };
+        if !config.enabled {
+            return Ok(vocabulary);
+        }
+        
+        let mut files: Vec<PathBuf> = vec![config_path.join(VOCABULARY_FILE)];
+        if let Ok(entries) = std::fs::read_dir(config_path.join(VOCABULARY_DIR)) {
+            let mut learned: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.extension().is_some_and(|e| e == "txt"))
+                .collect();
+            learned.sort();
+            files.extend(learned);
+        }
+        files.retain(|path| path.exists());
+        files.extend(config.files.iter().cloned());
+        
+        for path in &files {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read vocabulary file {}: {}", path.display(), e))?;
+            vocabulary.add_entries(&contents);
+        }
+        
+        Ok(vocabulary)
+    }
+    
+    fn add_entries(&mut self, contents: &str) {
+        let learned = contents.starts_with(LEARNED_VOCABULARY_HEADER);
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (spoken, written) = match line.split_once('=') {
+                Some((spoken, written)) => (spoken.trim().to_lowercase(), written.trim().to_string()),
+                None => (line.to_lowercase(), line.to_string()),
+            };
+            if spoken.is_empty() || written.is_empty() {
+                continue;
+            }
+            
+            let words = spoken.split_whitespace().count();
+            // Allow one word more than the spoken form, for terms heard as
+            // several words ("serde" as "sir day")
+            self.max_words = self.max_words.max(words + 1).min(LEARN_MAX_WORDS + 1);
+            self.entries.push(VocabularyEntry {
+                phonetic: phonetic_form(&spoken),
+                spoken,
+                written,
+                words,
+                learned,
+            });
+        }
+    }
+    
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    
+    /// Replace runs of words that sound like a vocabulary entry with the
+    /// entry, escaped like "literal" words so that post-processing leaves
+    /// it alone. Learned identifiers ("file name" for `file_name`) are
+    /// only applied in code mode, as they would rewrite ordinary sentences.
+    fn correct(&self, text: &str, mode: DictationMode, escaped: &mut Vec<String>) -> String {
+        if self.entries.is_empty() {
+            return text.to_string();
+        }
+        
+        let tokens: Vec<&str> = text.split_whitespace().collect();
+        let mut output = Vec::with_capacity(tokens.len());
+        let mut i = 0;
+        while i < tokens.len() {
+            // The best scoring window wins, the shortest one on a tie
+            let mut matched: Option<(&VocabularyEntry, usize, f64)> = None;
+            for n in 1..=self.max_words.min(tokens.len() - i) {
+                let window = &tokens[i..i + n];
+                if window.iter().any(|t| t.chars().any(is_placeholder)) {
+                    break;
+                }
+                if let Some((entry, score)) = self.best_match(window, mode) {
+                    if matched.is_none_or(|(_, _, best)| score > best) {
+                        matched = Some((entry, n, score));
+                    }
+                }
+            }
+            
+            match matched {
+                Some((entry, n, _)) => {
+                    output.push(char::from_u32(PLACEHOLDER_BASE + escaped.len() as u32).unwrap_or(' ').to_string());
+                    escaped.push(entry.written.clone());
+                    i += n;
+                }
+                None => {
+                    output.push(tokens[i].to_string());
+                    i += 1;
+                }
+            }
+        }
+        
+        output.join(" ")
+    }
+    
+    /// Entry sounding most like `window`, with its score. Every word more or
+    /// less than the entry's spoken form costs `WORD_COUNT_PENALTY`, so that
+    /// "tokio i" matches `tokio` on "tokio" alone.
+    fn best_match(&self, window: &[&str], mode: DictationMode) -> Option<(&VocabularyEntry, f64)> {
+        let phrase = window.join(" ");
+        let phonetic = phonetic_form(&phrase);
+        let mut best: Option<(&VocabularyEntry, f64)> = None;
+        for entry in self.entries.iter().filter(|entry| !entry.learned || mode == DictationMode::Code) {
+            let similarity = if entry.spoken == phrase {
+                1.0
+            } else if phonetic.len() < 3 || entry.phonetic.len() < 3 {
+                // Too short to tell apart from common words
+                continue;
+            } else {
+                strsim::normalized_levenshtein(&phonetic, &entry.phonetic)
+            };
+            let score = similarity - WORD_COUNT_PENALTY * window.len().abs_diff(entry.words) as f64;
+            if score >= self.threshold && best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((entry, score));
+            }
+        }
+        best
+    }
+    
+    /// Spoken forms made only of words the model knows, usable as a grammar
+    fn known_phrases(&self, model: &mut Model) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.spoken.split_whitespace().all(|word| model.find_word(word).is_some()))
+            .map(|entry| entry.spoken.clone())
+            .collect()
+    }
+}
+
+/// Score lost for every word a window has more or less than a vocabulary
+/// entry's spoken form
+const WORD_COUNT_PENALTY: f64 = 0.1;
+
+/// Rough pronunciation of `text`, so that terms can be matched to what the
+/// recognizer heard: spelling variants are unified, vowels are reduced to a
+/// front and a back one and repeated sounds collapsed ("sir day" and "serde"
+/// are both `serde`, while "taco" and "tokio" stay apart)
+fn phonetic_form(text: &str) -> String {
+    let letters: String = text.to_lowercase().chars().filter(|c| c.is_alphanumeric()).collect();
+    let letters = letters
+        .replace("ph", "f")
+        .replace("ck", "k")
+        .replace("qu", "kw")
+        .replace('x', "ks");
+    
+    let mut phonetic = String::with_capacity(letters.len());
+    for c in letters.chars() {
+        let c = match c {
+            'a' | 'e' | 'i' | 'y' => 'e',
+            'o' | 'u' => 'o',
+            'c' | 'q' => 'k',
+            'z' => 's',
+            c => c,
+        };
+        if !phonetic.ends_with(c) {
+            phonetic.push(c);
+        }
+    }
+    phonetic
+}
+
+/// Hesitation sounds removed from final results, by model language
+const BUILTIN_FILLERS: &[(&str, &[&str])] = &[
+    ("en", &["um", "uh", "uhm", "umm", "erm", "er", "hmm", "mm", "ah"]),
+    ("de", &["äh", "ähm", "öh", "hm", "hmm"]),
+    ("fr", &["euh", "heu", "hum", "bah"]),
+    ("es", &["eh", "em", "mmm"]),
+    ("it", &["ehm", "eh", "mmm"]),
+    ("pt", &["hum", "ahn", "éh"]),
+    ("nl", &["uh", "uhm", "eh", "ehm"]),
+    ("ru", &["э", "эм", "ээ", "мм"]),
+];
+
+// Shortest and longest phrase collapsed when it is repeated ("i want to i
+// want to go"). Single words are kept, as "that that" and "had had" are
+// often meant.
+const MIN_REPEATED_WORDS: usize = 2;
+const MAX_REPEATED_WORDS: usize = 4;
+
+/// Removes fillers and, in prose mode, immediately repeated phrases from
+/// final results. Words escaped with "literal" are kept.
+struct DisfluencyFilter {
+    /// Filler phrases, as words, longest first
+    fillers: Vec<Vec<String>>,
+    collapse_repetitions: bool,
+    /// Number words of the model language, when text2num knows it
+    numbers: Option<Language>,
+}
+
+impl DisfluencyFilter {
+    fn new(config: &CleanupConfig, language: &str) -> Self {
+        let builtin = BUILTIN_FILLERS
+            .iter()
+            .filter(|(code, _)| *code == language)
+            .flat_map(|(_, fillers)| fillers.iter().map(|f| f.to_string()));
+        let configured = config.fillers.get(language).into_iter().flatten().map(|f| f.to_lowercase());
+        
+        let mut fillers: Vec<Vec<String>> = if config.remove_fillers {
+            builtin
+                .chain(configured)
+                .map(|filler| filler.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+                .filter(|words| !words.is_empty())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        fillers.sort_by_key(|words| std::cmp::Reverse(words.len()));
+        
+        DisfluencyFilter {
+            fillers,
+            collapse_repetitions: config.collapse_repetitions,
+            numbers: get_interpreter_for(language),
+        }
+    }
+    
+    fn clean(&self, text: &str, mode: DictationMode) -> String {
+        let tokens: Vec<&str> = text.split_whitespace().collect();
+        let mut output: Vec<&str> = Vec::with_capacity(tokens.len());
+        
+        let mut i = 0;
+        'tokens: while i < tokens.len() {
+            for filler in &self.fillers {
+                let end = i + filler.len();
+                if end <= tokens.len() && tokens[i..end].iter().copied().eq(filler.iter().map(String::as_str)) {
+                    i = end;
+                    continue 'tokens;
+                }
+            }
+            output.push(tokens[i]);
+            i += 1;
+            
+            // Repeated symbol names ("close paren close paren") are code
+            if self.collapse_repetitions && mode == DictationMode::Prose {
+                self.drop_repetition(&mut output);
+            }
+        }
+        
+        output.join(" ")
+    }
+    
+    /// Drop the last words of `output` when they repeat the ones before
+    /// them. Numbers are kept, since "twenty twenty" or "zero zero seven"
+    /// repeat on purpose.
+    fn drop_repetition(&self, output: &mut Vec<&str>) {
+        let is_number = |word: &&str| {
+            word.parse::<f64>().is_ok() || self.numbers.as_ref().is_some_and(|numbers| text2digits(word, numbers).is_ok())
+        };
+        for n in (MIN_REPEATED_WORDS..=MAX_REPEATED_WORDS).rev() {
+            if output.len() < 2 * n {
+                continue;
+            }
+            let (first, second) = output[output.len() - 2 * n..].split_at(n);
+            if first == second && !second.iter().any(is_number) {
+                output.truncate(output.len() - n);
+                return;
+            }
+        }
+    }
+}
+
+fn lookup(table: &[(&str, &'static str)], word: &str) -> Option<&'static str> {
+    table.iter().find(|(w, _)| *w == word).map(|(_, s)| *s)
+}
+
+/// Digit of a single number word such as "seven" or "twenty"
+fn number_word(word: &str) -> Option<&'static str> {
+    lookup(DIGIT_WORDS, word).or_else(|| lookup(AMBIGUOUS_NUMBERS, word))
+}
+
+fn is_numeric(token: &str) -> bool {
+    token.parse::<f64>().is_ok() || number_word(token).is_some()
+}
+
+/// Stands in for a number word kept away from text2num, see
+/// `convert_number_phrases`
+const HELD_NUMBER: char = '\u{E003}';
+
+/// Convert numbers spoken as several words ("twenty five", "two point
+/// five") to digits. Number words standing alone ("ten minutes") are left
+/// to `resolve_ambiguous_words`, which only converts them in context.
+fn convert_number_phrases(text: &str) -> String {
+    let is_number_part = |word: &str| number_word(word).is_some() || matches!(word, "point" | "million" | "billion");
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let mut held = Vec::new();
+    let mut words = Vec::with_capacity(tokens.len());
+    for (i, token) in tokens.iter().enumerate() {
+        let alone = !((i > 0 && is_number_part(tokens[i - 1]))
+            || tokens.get(i + 1).is_some_and(|next| is_number_part(next)));
+        if alone && lookup(AMBIGUOUS_NUMBERS, token).is_some() {
+            held.push(*token);
+            words.push(HELD_NUMBER.to_string());
+        } else {
+            words.push(token.to_string());
+        }
+    }
+    
+    // Digits below ten are left alone by the threshold
+    let converted = convert_words_to_numbers(&words.join(" "), 10.0);
+    let mut held = held.into_iter();
+    let mut result = String::with_capacity(converted.len());
+    for c in converted.chars() {
+        match c {
+            HELD_NUMBER => result.push_str(held.next().unwrap_or_default()),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Convert number words and operator words that are also everyday English
+/// ("one", "ten", "times", ...) only where the context makes them code:
+/// - after "number" or "symbol", which is dropped ("symbol colon" is `:`)
+/// - operators between two numbers ("three times four"), or a `-` before a
+///   number where no operand precedes it ("equals minus one")
+/// - "dot" and "colon" between two words or numbers ("self dot name"),
+///   "colon" next to another "colon" (`::`)
+/// - number words next to a number, a symbol or a converted operator
+///
+/// so that "this one works", "ten minutes" and "three times" are left alone.
+fn resolve_ambiguous_words(text: &str) -> String {
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let mut resolved: Vec<Option<String>> = vec![None; tokens.len()];
+    let mut prefix = vec![false; tokens.len()];
+    
+    for i in 0..tokens.len().saturating_sub(1) {
+        let next = tokens[i + 1];
+        let forced = match tokens[i] {
+            "number" => number_word(next),
+            "symbol" => lookup(AMBIGUOUS_OPERATORS, next).or_else(|| number_word(next)),
+            _ => None,
+        };
+        if let Some(symbol) = forced.filter(|_| !prefix[i] && resolved[i].is_none()) {
+            prefix[i] = true;
+            resolved[i + 1] = Some(symbol.to_string());
+        }
+    }
+    
+    let numeric_at = |i: Option<usize>| i.and_then(|i| tokens.get(i)).is_some_and(|t| is_numeric(t));
+    let operand_at = |i: Option<usize>| i.and_then(|i| tokens.get(i)).is_some_and(|t| is_numeric(t) || is_word(t));
+    // "dot" and "colon" are ordinary words too ("polka dot dress"), so they
+    // only convert after a number or something that is clearly code
+    let code_at = |i: Option<usize>, resolved: &[Option<String>]| {
+        i.and_then(|i| tokens.get(i)).is_some_and(|t| is_numeric(t) || is_identifier(t))
+            // The next member of a chain, "self dot user dot name"
+            || i.and_then(|i| i.checked_sub(1)).is_some_and(|i| resolved[i].as_deref() == Some("."))
+    };
+    for i in 0..tokens.len() {
+        if prefix[i] || resolved[i].is_some() {
+            continue;
+        }
+        let Some(symbol) = lookup(AMBIGUOUS_OPERATORS, tokens[i]) else {
+            continue;
+        };
+        let before = i.checked_sub(1);
+        let after = Some(i + 1);
+        let converts = match tokens[i] {
+            "colon" => before.is_some_and(|b| tokens[b] == "colon") || tokens.get(i + 1) == Some(&"colon")
+                || (code_at(before, &resolved) && operand_at(after)),
+            "dot" => code_at(before, &resolved) && operand_at(after),
+            "minus" => numeric_at(after)
+                && (numeric_at(before) || before.is_none_or(|b| is_operator(tokens[b]) || "([{,=".contains(tokens[b]))),
+            _ => numeric_at(before) && numeric_at(after),
+        };
+        if converts {
+            resolved[i] = Some(symbol.to_string());
+        }
+    }
+    
+    let context: Vec<bool> = (0..tokens.len())
+        .map(|i| {
+            !prefix[i]
+                && (resolved[i].is_some()
+                    || tokens[i].parse::<f64>().is_ok()
+                    || tokens[i].chars().all(|c| !c.is_alphanumeric() && !is_placeholder(c)))
+        })
+        .collect();
+    for i in 0..tokens.len() {
+        if prefix[i] || resolved[i].is_some() {
+            continue;
+        }
+        if let Some(digit) = number_word(tokens[i]) {
+            let near_code = (i > 0 && context[i - 1]) || context.get(i + 1).copied().unwrap_or(false);
+            if near_code {
+                resolved[i] = Some(digit.to_string());
+            }
+        }
+    }
+    
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !prefix[*i])
+        .map(|(i, t)| resolved[i].clone().unwrap_or_else(|| t.to_string()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether a space goes between two code tokens. `prev_unary` tells
+/// whether `prev` is a unary operator such as the `-` of `-1`.
+fn code_separator(prev: &str, next: &str, prev_unary: bool, rules: &SpacingRules) -> bool {
+    match (prev, next) {
+        (_, ";" | "," | "?") => false,
+        ("@", _) => false,
+        (",", _) => rules.after_comma,
+        (".", _) | (_, ".") | ("::", _) | (_, "::") => false,
+        (_, ":") => false,
+        (":", _) => rules.after_colon,
+        ("(", ")") | ("[", "]") | ("{", "}") => false,
+        (_, ")") | ("(", _) => rules.inside_parens,
+        (_, "]") | ("[", _) => rules.inside_brackets,
+        (_, "}") | ("{", _) => rules.inside_braces,
+        _ if prev_unary => false,
+        (")" | "]", "(" | "[") => false,
+        (_, "(") if is_word(prev) => rules.keywords_before_paren.iter().any(|k| k == prev),
+        (_, "[") if is_word(prev) => false,
+        _ if is_operator(prev) || is_operator(next) => rules.around_operators,
+        _ => true,
+    }
+}
+
+/// Join the symbols produced by `NUMBER_PATTERNS` to the surrounding words
+/// following `rules`, so that "foo ( bar , 1 )" becomes "foo(bar, 1)"
+fn format_code(text: &str, rules: &SpacingRules) -> String {
+    // Merge operators spoken as several words, e.g. "equals equals" or "plus equals"
+    let mut tokens: Vec<String> = Vec::new();
+    for token in text.split_whitespace() {
+        if let Some(last) = tokens.last_mut() {
+            let merges = (is_operator(last) && is_operator(token) && last.len() < 3
+                && (token == "=" || token == last.as_str()))
+                || (last == ":" && token == ":");
+            if merges {
+                last.push_str(token);
+                continue;
+            }
+        }
+        tokens.push(token.to_string());
+    }
+    
+    let mut result = String::with_capacity(text.len());
+    let mut prev_unary = false;
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && code_separator(&tokens[i - 1], token, prev_unary, rules) {
+            result.push(' ');
+        }
+        result.push_str(token);
+        
+        // An operator is unary when nothing it could apply to comes before it
+        prev_unary = matches!(token.as_str(), "-" | "+" | "!" | "*" | "&")
+            && (i == 0 || {
+                let before = tokens[i - 1].as_str();
+                is_operator(before) || matches!(before, "(" | "[" | "{" | "," | ":" | ";" | "return")
+            });
+    }
+    
+    result
+}
+
+fn enhance_transcription(
+    text: &str,
+    mode: DictationMode,
+    profile: &LanguageProfile,
+    vocabulary: &Vocabulary,
+    disfluencies: &DisfluencyFilter,
+    sentence_start: bool,
+) -> String {
+    let (text, mut escaped) = protect_escapes(&text.to_lowercase());
+    let text = disfluencies.clean(&text, mode);
+    let text = vocabulary.correct(&text, mode, &mut escaped);
+    
+    let result = match mode {
+        DictationMode::Prose => format_prose(&text, sentence_start),
+        DictationMode::Code => format_code_dictation(&text, profile),
+    };
+    
+    restore_escapes(&result, &escaped)
+}
+
+fn format_code_dictation(text: &str, profile: &LanguageProfile) -> String {
+    if let Some(snippet) = profile.expand_snippet(text) {
+        return snippet;
+    }
+    
+    let mut result = text.to_string();
+    for (pattern, replacement) in profile.symbols.iter() {
+        result = pattern.replace_all(&result, replacement.as_str()).to_string();
+    }
+    
+    // Then handle complex number conversions, leaving isolated number words
+    // to `resolve_ambiguous_words`
+    result = convert_number_phrases(&result);
+    
+    // Then apply simple pattern replacements
+    for (pattern, replacement) in NUMBER_PATTERNS.iter() {
+        result = pattern.replace_all(&result, *replacement).to_string();
+    }
+    
+    result = resolve_ambiguous_words(&result);
+    result = profile.apply_keyword_casing(&result);
+    
+    format_code(&result, &profile.spacing)
+}
+
+/// Resolve which model to use, installing it if needed, and load it.
+/// Returns the model and its language code.
+async fn load_model(args: &Cli, config: &Config, models_dir: &Path) -> Result<(Model, String), Box<dyn std::error::Error>> {
+    let mut manifest = ModelManifest::load(models_dir)?;
+    manifest.reindex(models_dir);
+
+    let requested = match (&args.model, &config.model) {
+        (Some(id), _) => Some((id.as_str(), "given with --model")),
+        // The config crate merges config.toml and SCRIBA_* variables, so
+        // check the environment to name where the id actually came from
+        (None, Some(id)) if std::env::var("SCRIBA_MODEL").is_ok_and(|value| value == *id) => Some((id.as_str(), "set with SCRIBA_MODEL")),
+        (None, Some(id)) => Some((id.as_str(), "configured in config.toml")),
+        (None, None) => None,
+    };
+
+    let first_installed = manifest.speech_models().next().cloned();
+
+    // Pick the model to load: explicit selection, then --model or config,
+    // then the last used one, then whatever is installed, and finally prompt.
+    let (model_id, reason) = if args.select_model {
+        let selected_model = choose_model_to_install(args.yes, &manifest, models_dir)?;
+        install_model(&selected_model, models_dir, &mut manifest, &config.download).await?;
+        let reason = if args.yes { "recommended default" } else { "selected interactively" };
+        (selected_model.id().to_string(), reason.to_string())
+    } else if let Some((id, reason)) = requested {
+        if id == SPEAKER_MODEL.id() {
+            return Err(format!("'{}' is the speaker identification model, not a speech model", id).into());
+        }
+        if !manifest.models.contains_key(id) {
+            let model = find_model_info(id)
+                .ok_or_else(|| format!("Model '{}' is not installed and is not a known model id", id))?;
+            install_model(model, models_dir, &mut manifest, &config.download).await?;
+        }
+        (id.to_string(), reason.to_string())
+    } else if let Some(id) = manifest.last_used.clone() {
+        (id, "last used model".to_string())
+    } else if let Some(id) = first_installed {
+        let count = manifest.speech_models().count();
+        let reason = if count == 1 {
+            "only installed model".to_string()
+        } else {
+            format!("first of {} installed models", count)
+        };
+        (id, reason)
+    } else {
+        let selected_model = choose_model_to_install(args.yes, &manifest, models_dir)?;
+        install_model(&selected_model, models_dir, &mut manifest, &config.download).await?;
+        let reason = if args.yes { "recommended default" } else { "selected interactively" };
+        (selected_model.id().to_string(), reason.to_string())
+    };
+
+    let installed = manifest.models.get(&model_id)
+        .ok_or_else(|| format!("Model '{}' is not installed", model_id))?
+        .clone();
+    let model_dir = models_dir.join(&installed.path);
+
+    manifest.last_used = Some(model_id.clone());
+    manifest.save(models_dir)?;
+
+    info!("Using model: {} [{}] ({})", installed.name, model_id, reason);
+    info!("Model path: {}", model_dir.display());
+
+    // Load Vosk model
+    let model = Model::new(model_dir.to_str().ok_or("Invalid model path")?)
+        .ok_or("Failed to load model. Make sure the model exists at the specified path.")?;
+    
+    let language = match (&config.language, find_model_info(&model_id)) {
+        (Some(language), _) => language.to_lowercase(),
+        (None, Some(info)) => info.language_code().0.to_string(),
+        (None, None) => {
+            warn!("The language of model '{}' is unknown, assuming English; set `language` in config.toml", model_id);
+            "en".to_string()
+        }
+    };
+    
+    Ok((model, language))
+}
+
+/// Load the speaker identification model, installing it on first use
+async fn load_speaker_model(config: &Config, models_dir: &Path) -> Result<SpeakerModel, Box<dyn std::error::Error>> {
+    let mut manifest = ModelManifest::load(models_dir)?;
+    manifest.reindex(models_dir);
+    
+    if !manifest.models.contains_key(SPEAKER_MODEL.id()) {
+        install_model(&SPEAKER_MODEL, models_dir, &mut manifest, &config.download).await?;
+    }
+    
+    let installed = &manifest.models[SPEAKER_MODEL.id()];
+    let model_dir = models_dir.join(&installed.path);
+    info!("Using speaker model: {}", model_dir.display());
+    
+    SpeakerModel::new(model_dir.to_str().ok_or("Invalid speaker model path")?)
+        .ok_or_else(|| "Failed to load the speaker identification model".into())
+}
+
+/// `scriba enroll-speaker`: record a speaker for a while and store their
+/// averaged speaker vector under `name`.
+async fn run_enroll_speaker(args: &Cli, config: &Config, config_path: &Path, models_dir: &Path, name: &str, seconds: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let (model, _) = load_model(args, config, models_dir).await?;
+    let speaker_model = load_speaker_model(config, models_dir).await?;
+    let mut processor = AudioProcessor::new(&model, args.sample_rate as f32, None)?;
+    processor.enable_speakers(&speaker_model, None);
+    
+    let ring = Arc::new(AudioRingBuffer::new(args.sample_rate as usize, OverflowPolicy::DropOldest));
+    setup_audio_stream(args.sample_rate, CaptureDevice::Microphone(config.audio.device.clone()), ring.clone())?;
+    
+    println!("🎤 Enrolling '{}'. Please speak normally for {} seconds...", name, seconds);
+    
+    // Decode on a dedicated thread, like the main decoder, to keep the
+    // runtime free
+    let mut chunk = vec![0.0f32; config.audio.chunk_size.clamp(1, args.sample_rate as usize)];
+    let deadline = Instant::now() + Duration::from_secs(seconds);
+    let (tags_tx, tags_rx) = tokio::sync::oneshot::channel();
+    std::thread::Builder::new().name("scriba-decoder".to_string()).spawn(move || {
+        let mut tags = Vec::new();
+        while Instant::now() < deadline {
+            if !ring.read_chunk(&mut chunk) {
+                std::thread::sleep(AUDIO_POLL_INTERVAL);
+                continue;
+            }
+            match processor.process_audio(&convert_f32_to_i16(&chunk)) {
+                Ok(result) => tags.extend(result.and_then(|result| result.speaker)),
+                Err(e) => error!("Audio processing error: {}", e),
+            }
+        }
+        tags.extend(processor.finish_utterance().and_then(|result| result.speaker));
+        let _ = tags_tx.send(tags);
+    })?;
+    let tags = tags_rx.await.map_err(|_| "Decoder thread panicked")?;
+    
+    // Average the vectors, weighted by how much speech each one covers
+    let total_frames: f32 = tags.iter().map(|tag| tag.frames as f32).sum();
+    if total_frames == 0.0 {
+        return Err("No speech was detected, so no speaker vector could be computed. Try again and speak louder or longer.".into());
+    }
+    let mut vector = vec![0.0f32; tags[0].vector.len()];
+    for tag in &tags {
+        let weight = tag.frames as f32 / total_frames;
+        for (v, x) in vector.iter_mut().zip(&tag.vector) {
+            *v += x * weight;
+        }
+    }
+    
+    let mut enrolled = EnrolledSpeakers::load(config_path)?;
+    enrolled.speakers.insert(name.to_string(), vector);
+    enrolled.save(config_path)?;
+    
+    println!("✅ Enrolled '{}' from {} utterance(s).", name, tags.len());
+    
+    Ok(())
+}
+
+/// Peak level treated as clipping
+const CLIPPING_THRESHOLD: f32 = 0.99;
+
+/// Levels below this are drawn as an empty meter
+const METER_FLOOR_DB: f32 = -60.0;
+
+fn level_meter(rms: f32, peak: f32) -> String {
+    const WIDTH: usize = 40;
+    let filled = (((rms - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0) * WIDTH as f32) as usize;
+    let peak_db = 20.0 * peak.max(f32::MIN_POSITIVE).log10();
+    
+    format!(
+        "[{}{}] RMS {:>6.1} dBFS  peak {:>6.1} dBFS{}",
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        rms.max(METER_FLOOR_DB - 1.0),
+        peak_db.max(METER_FLOOR_DB - 1.0),
+        if peak >= CLIPPING_THRESHOLD { "  ⚠ CLIPPING" } else { "            " },
+    )
+}
+
+/// `scriba mic-test`: show a live level meter for the input device and
+/// optionally record, play back or transcribe what was captured.
+#[allow(clippy::too_many_arguments)]
+async fn run_mic_test(
+    args: &Cli,
+    config: &Config,
+    config_path: &Path,
+    models_dir: &Path,
+    seconds: u64,
+    record: Option<&Path>,
+    playback: bool,
+    transcribe: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ring = Arc::new(AudioRingBuffer::new(args.sample_rate as usize, OverflowPolicy::DropOldest));
+    setup_audio_stream(args.sample_rate, CaptureDevice::Microphone(config.audio.device.clone()), ring.clone())?;
+    
+    println!("🎤 Listening for {} seconds, speak normally. Press Ctrl+C to stop early.", seconds);
+    
+    let keep_audio = record.is_some() || playback || transcribe;
+    let mut captured: Vec<f32> = Vec::new();
+    let window_size = (args.sample_rate / 10) as usize; // ~100ms per meter update
+    let mut block = vec![0.0f32; window_size];
+    
+    let mut clipped_windows = 0;
+    let mut loudest = f32::NEG_INFINITY;
+    let deadline = tokio::time::sleep(Duration::from_secs(seconds));
+    tokio::pin!(deadline);
+    
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = tokio::signal::ctrl_c() => break,
+            _ = tokio::time::sleep(AUDIO_POLL_INTERVAL) => {
+                while ring.read_chunk(&mut block) {
+                    if keep_audio {
+                        captured.extend_from_slice(&block);
+                    }
+                    
+                    let rms = rms_db(&block);
+                    let peak = block.iter().fold(0.0f32, |max, s| max.max(s.abs()));
+                    
+                    if peak >= CLIPPING_THRESHOLD {
+                        clipped_windows += 1;
+                    }
+                    loudest = loudest.max(rms);
+                    
+                    print!("\r{}", level_meter(rms, peak));
+                    std::io::stdout().flush()?;
+                }
+            }
+        }
+    }
+    println!();
+    println!();
+    
+    if loudest < -50.0 {
+        println!("⚠ The input is very quiet. Check that the microphone is not muted and the input volume is up.");
+    } else if clipped_windows > 0 {
+        println!("⚠ The input clipped {} times. Lower the input volume or move away from the microphone.", clipped_windows);
+    } else {
+        println!("✅ Input levels look fine (loudest RMS {:.1} dBFS).", loudest);
+    }
+    
+    if let Some(path) = record {
+        write_wav(path, &captured, args.sample_rate)?;
+        println!("💾 Saved {:.1}s of audio to {}", captured.len() as f64 / args.sample_rate as f64, path.display());
+    }
+    
+    if playback {
+        println!("🔊 Playing back the recording...");
+        play_samples(&captured, args.sample_rate).await?;
+    }
+    
+    if transcribe {
+        let (mut model, language) = load_model(args, config, models_dir).await?;
+        let vocabulary = Vocabulary::load(config_path, &config.vocabulary)?;
+        let grammar = config.vocabulary.command_only.then(|| vocabulary.known_phrases(&mut model));
+        let mut processor = AudioProcessor::new(&model, args.sample_rate as f32, grammar.as_deref())?;
+        
+        let mode = args.mode.or(config.mode).unwrap_or(DictationMode::Code);
+        let disfluencies = DisfluencyFilter::new(&config.cleanup, &language);
+        let profiles = Profiles::load(config, args.profile.as_deref())?;
+        let mut output = OutputContext::new();
+        let mut texts = Vec::new();
+        for chunk in convert_f32_to_i16(&captured).chunks(config.audio.chunk_size.max(1)) {
+            if let Some(result) = processor.process_audio(chunk)? {
+                if result.is_final {
+                    let text = enhance_transcription(&result.text, mode, profiles.active(), &vocabulary, &disfluencies, output.at_sentence_start());
+                    texts.push(output.join(&text));
+                }
+            }
+        }
+        if let Some(result) = processor.finish_utterance() {
+            let text = enhance_transcription(&result.text, mode, profiles.active(), &vocabulary, &disfluencies, output.at_sentence_start());
+            texts.push(output.join(&text));
+        }
+        
+        if texts.is_empty() {
+            println!("📝 Nothing was recognized.");
+        } else {
+            println!("📝 {}", texts.concat().replace(CURSOR_MARKER, ""));
+        }
+    }
+    
+    Ok(())
+}
+
+fn write_wav(path: &Path, samples: &[f32], sample_rate: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for sample in convert_f32_to_i16(samples) {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()?;
+    
+    Ok(())
+}
+
+/// Line of the `.jsonl` transcript written next to recorded sessions
+#[derive(Serialize)]
+struct TranscriptEntry<'a> {
+    text: &'a str,
+    confidence: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speaker: Option<&'a str>,
+    /// Cluster of a speaker that isn't enrolled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speaker_cluster: Option<usize>,
+    /// WAV file holding the utterance, relative to the recording directory
+    audio: &'a str,
+    /// Offsets of the utterance within `audio`, in seconds
+    start: f64,
+    end: f64,
+}
+
+/// Writes the audio fed to the recognizer to WAV files and links every
+/// final result to its audio segment in a transcript file (`--record`).
+struct SessionRecorder {
+    dir: PathBuf,
+    session: String,
+    mode: RecordMode,
+    sample_rate: u32,
+    transcript: File,
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+    audio_file: String,
+    /// Samples written to the current WAV file
+    file_samples: u64,
+    /// Position in the current WAV file where the current utterance starts
+    utterance_start: u64,
+    utterance_index: u32,
+}
+
+impl SessionRecorder {
+    fn new(dir: &Path, mode: RecordMode, sample_rate: u32) -> Result<Self, Box<dyn std::error::Error>> {
+        create_dir_all(dir)?;
+        let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let session = format!("session-{}", started);
+        let transcript = File::create(dir.join(format!("{}.jsonl", session)))?;
+        
+        info!("Recording session to {}", dir.display());
+        
+        Ok(SessionRecorder {
+            dir: dir.to_path_buf(),
+            session,
+            mode,
+            sample_rate,
+            transcript,
+            writer: None,
+            audio_file: String::new(),
+            file_samples: 0,
+            utterance_start: 0,
+            utterance_index: 1,
+        })
+    }
+    
+    fn write(&mut self, samples: &[i16]) -> Result<(), Box<dyn std::error::Error>> {
+        if self.writer.is_none() {
+            // Open files lazily so utterance mode doesn't leave an empty trailing file
+            self.audio_file = match self.mode {
+                RecordMode::Session => format!("{}.wav", self.session),
+                RecordMode::Utterance => format!("{}-{:04}.wav", self.session, self.utterance_index),
+            };
+            let spec = hound::WavSpec {
+                channels: 1,
+                sample_rate: self.sample_rate,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            self.writer = Some(hound::WavWriter::create(self.dir.join(&self.audio_file), spec)?);
+            self.file_samples = 0;
+            self.utterance_start = 0;
+        }
+        
+        if let Some(writer) = &mut self.writer {
+            for &sample in samples {
+                writer.write_sample(sample)?;
+            }
+            self.file_samples += samples.len() as u64;
+        }
+        
+        Ok(())
+    }
+    
+    /// Record `result` as covering the audio written since the previous final
+    fn finish_utterance(&mut self, result: &TranscriptionResult) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(writer) = &mut self.writer else {
+            return Ok(());
+        };
+        // Keep the WAV header valid in case scriba is killed mid-session
+        writer.flush()?;
+        
+        let entry = TranscriptEntry {
+            text: &result.text,
+            confidence: result.confidence,
+            speaker: result.speaker.as_ref().map(|speaker| speaker.label.as_str()),
+            speaker_cluster: result.speaker.as_ref().and_then(|speaker| speaker.cluster),
+            audio: &self.audio_file,
+            start: self.utterance_start as f64 / self.sample_rate as f64,
+            end: self.file_samples as f64 / self.sample_rate as f64,
+        };
+        writeln!(self.transcript, "{}", serde_json::to_string(&entry)?)?;
+        self.transcript.flush()?;
+        
+        self.utterance_index += 1;
+        self.utterance_start = self.file_samples;
+        if self.mode == RecordMode::Utterance {
+            if let Some(writer) = self.writer.take() {
+                writer.finalize()?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Forget the audio written since the previous final after an utterance
+    /// without any text, so it isn't merged into the next segment
+    fn discard_utterance(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.utterance_start = self.file_samples;
+        if self.mode == RecordMode::Utterance {
+            if let Some(writer) = self.writer.take() {
+                writer.finalize()?;
+                std::fs::remove_file(self.dir.join(&self.audio_file))?;
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+/// Directories never scanned by `scriba learn`: build output, dependencies
+/// and virtual environments
+const LEARN_SKIPPED_DIRS: &[&str] = &["target", "node_modules", "dist", "build", "venv", "__pycache__", "vendor"];
+
+const LEARN_SOURCE_EXTENSIONS: &[&str] = &["rs", "py", "js", "jsx", "ts", "tsx", "mjs", "cjs"];
+
+// Identifiers made only of these words ("and_then", "is_some") are
+// skipped, as they would replace everyday phrases
+const LEARN_COMMON_WORDS: &[&str] = &[
+    "a", "an", "and", "as", "at", "be", "by", "do", "for", "from", "get", "has", "if", "in",
+    "into", "is", "it", "new", "no", "not", "of", "on", "or", "set", "so", "some", "the",
+    "then", "to", "with",
+];
+
+// Longest identifiers learned, in words, so that they can still be matched
+// by `Vocabulary::correct`
+const LEARN_MAX_WORDS: usize = 5;
+
+static IDENTIFIER: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b[A-Za-z_][A-Za-z0-9_]*\b").unwrap());
+static CODE_SPAN: Lazy<Regex> = Lazy::new(|| Regex::new(r"`([^`\s]+)`").unwrap());
+// Modules imported by Rust, Python and JavaScript code
+static IMPORTED_MODULE: Lazy<Regex> = Lazy::new(|| Regex::new(
+    r#"(?m)\buse\s+([a-z_][a-z0-9_]*)::|\bextern crate\s+([a-z_][a-z0-9_]*)|^\s*(?:from|import)\s+([A-Za-z_]\w*)|(?:from|require\()\s*['"]([@A-Za-z][\w.-]*)"#
+).unwrap());
+
+/// Lowercase words of an identifier in any casing: `get_user_by_id`,
+/// `HTTPClient` and `XMLHttpRequest` give `get user by id`, `http client`
+/// and `xml http request`. The last capital of an acronym followed by
+/// lowercase letters starts the next word.
+fn identifier_parts(identifier: &str) -> Vec<String> {
+    let chars: Vec<char> = identifier.chars().collect();
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_ascii_alphanumeric() {
+            if !part.is_empty() {
+                parts.push(std::mem::take(&mut part));
+            }
+            continue;
+        }
+        if let Some(&prev) = i.checked_sub(1).and_then(|i| chars.get(i)) {
+            let next = chars.get(i + 1).copied().unwrap_or_default();
+            let boundary = (prev.is_ascii_digit() != c.is_ascii_digit())
+                || (prev.is_ascii_lowercase() && c.is_ascii_uppercase())
+                || (prev.is_ascii_uppercase() && c.is_ascii_uppercase() && next.is_ascii_lowercase());
+            if boundary && !part.is_empty() {
+                parts.push(std::mem::take(&mut part));
+            }
+        }
+        part.push(c.to_ascii_lowercase());
+    }
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    parts
+}
+
+/// Identifiers found in a repository, by spoken form, with how often each
+/// spelling was seen
+#[derive(Default)]
+struct LearnedVocabulary {
+    spellings: HashMap<String, HashMap<String, usize>>,
+}
+
+impl LearnedVocabulary {
+    /// Count `identifier` when it is made of several words, or always when
+    /// `always` is set (module and package names)
+    fn add(&mut self, identifier: &str, always: bool, weight: usize) {
+        let parts = identifier_parts(identifier);
+        let is_short = identifier.len() < 4;
+        if parts.is_empty() || parts.len() > LEARN_MAX_WORDS || is_short || (parts.len() < 2 && !always) {
+            return;
+        }
+        if parts.iter().all(|part| LEARN_COMMON_WORDS.contains(&part.as_str())) {
+            return;
+        }
+        
+        let spoken = parts
+            .iter()
+            .map(|part| match part.parse::<u8>() {
+                Ok(digit) if digit < 10 => DIGIT_WORDS[digit as usize].0.to_string(),
+                _ => part.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        *self.spellings.entry(spoken).or_default().entry(identifier.to_string()).or_default() += weight;
+    }
+    
+    fn scan_source(&mut self, contents: &str, min_count: usize) {
+        for identifier in IDENTIFIER.find_iter(contents) {
+            self.add(identifier.as_str(), false, 1);
+        }
+        for caps in IMPORTED_MODULE.captures_iter(contents) {
+            if let Some(module) = caps.iter().skip(1).flatten().next() {
+                // Scoped npm packages (`@scope/name`) are spoken by their name
+                let name = module.as_str().rsplit('/').next().unwrap_or_default();
+                self.add(name, true, min_count);
+            }
+        }
+    }
+    
+    fn scan_readme(&mut self, contents: &str, min_count: usize) {
+        for caps in CODE_SPAN.captures_iter(contents) {
+            for identifier in IDENTIFIER.find_iter(&caps[1]) {
+                self.add(identifier.as_str(), true, min_count);
+            }
+        }
+    }
+    
+    /// Package and dependency names from `Cargo.toml` and `package.json`
+    fn scan_manifest(&mut self, file_name: &str, contents: &str, min_count: usize) {
+        let tables: Vec<(String, Vec<String>)> = match file_name {
+            "Cargo.toml" => {
+                let Ok(manifest) = contents.parse::<toml::Table>() else {
+                    return;
+                };
+                let name = manifest.get("package").and_then(|p| p.get("name")).and_then(|n| n.as_str());
+                let dependencies = ["dependencies", "dev-dependencies", "build-dependencies"]
+                    .iter()
+                    .filter_map(|key| manifest.get(*key).and_then(|d| d.as_table()))
+                    .flat_map(|table| table.keys().cloned())
+                    .collect();
+                vec![(name.unwrap_or_default().to_string(), dependencies)]
+            }
+            "package.json" => {
+                let Ok(manifest) = serde_json::from_str::<serde_json::Value>(contents) else {
+                    return;
+                };
+                let name = manifest["name"].as_str().unwrap_or_default();
+                let dependencies = ["dependencies", "devDependencies"]
+                    .iter()
+                    .filter_map(|key| manifest[*key].as_object())
+                    .flat_map(|object| object.keys().cloned())
+                    .collect();
+                vec![(name.to_string(), dependencies)]
+            }
+            _ => return,
+        };
+        
+        for (name, dependencies) in tables {
+            for package in std::iter::once(name).chain(dependencies).filter(|n| !n.is_empty()) {
+                let package = package.rsplit('/').next().unwrap_or_default().to_string();
+                // Crates are imported with underscores, but spoken the same
+                self.add(&package.replace('-', "_"), true, min_count);
+            }
+        }
+    }
+    
+    /// Scan `dir` recursively. Entries that can't be read are skipped with a
+    /// warning rather than ending the scan.
+    fn scan_directory(&mut self, dir: &Path, min_count: usize, files: &mut usize) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Skipping {}: {}", dir.display(), e);
+                return;
+            }
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Skipping an entry of {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    warn!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if file_type.is_dir() {
+                if !LEARN_SKIPPED_DIRS.contains(&name.as_str()) {
+                    self.scan_directory(&path, min_count, files);
+                }
+                continue;
+            }
+            if !file_type.is_file() {
+                continue;
+            }
+            
+            let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+            let is_source = LEARN_SOURCE_EXTENSIONS.contains(&extension);
+            let is_readme = name.to_lowercase().starts_with("readme");
+            let is_manifest = matches!(name.as_str(), "Cargo.toml" | "package.json");
+            if !is_source && !is_readme && !is_manifest {
+                continue;
+            }
+            // Skip binary and non-UTF-8 files
+            let Ok(contents) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            
+            if is_source {
+                self.scan_source(&contents, min_count);
+            } else if is_readme {
+                self.scan_readme(&contents, min_count);
+            } else {
+                self.scan_manifest(&name, &contents, min_count);
+            }
+            *files += 1;
+        }
+    }
+    
+    /// Vocabulary lines for the identifiers seen at least `min_count` times,
+    /// most frequent first, each spoken form typed with its most common spelling
+    fn entries(&self, min_count: usize, max_entries: usize) -> Vec<String> {
+        let mut entries: Vec<(usize, String)> = self.spellings
+            .iter()
+            .filter_map(|(spoken, spellings)| {
+                let (identifier, _) = spellings.iter().max_by_key(|(identifier, count)| (**count, std::cmp::Reverse(identifier.as_str())))?;
+                let total: usize = spellings.values().sum();
+                let line = if *spoken == identifier.to_lowercase() {
+                    identifier.clone()
+                } else {
+                    format!("{} = {}", spoken, identifier)
+                };
+                (total >= min_count).then_some((total, line))
+            })
+            .collect();
+        entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        entries.into_iter().take(max_entries).map(|(_, line)| line).collect()
+    }
+}
+
+/// FNV-1a hash of `path`, stable across runs and Rust versions
+fn path_hash(path: &Path) -> u32 {
+    path.to_string_lossy().bytes().fold(0x811c9dc5, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
+/// Scan the repository at `path` for identifiers, module and package names
+/// and write them as a vocabulary file
+fn run_learn(config_path: &Path, path: &Path, output: Option<&Path>, min_count: usize, max_entries: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let path = path.canonicalize().map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    if !path.is_dir() {
+        return Err(format!("{} is not a directory", path.display()).into());
+    }
+    let repository = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "repository".to_string());
+    
+    println!("🔎 Scanning {}...", path.display());
+    let mut learned = LearnedVocabulary::default();
+    let mut files = 0;
+    learned.scan_directory(&path, min_count, &mut files);
+    let entries = learned.entries(min_count, max_entries);
+    
+    let output = match output {
+        Some(output) => output.to_path_buf(),
+        None => {
+            let dir = config_path.join(VOCABULARY_DIR);
+            create_dir_all(&dir)?;
+            // Repositories with the same directory name get their own file
+            dir.join(format!("{}-{:08x}.txt", repository, path_hash(&path)))
+        }
+    };
+    let mut file = BufWriter::new(File::create(&output)?);
+    writeln!(file, "{}", LEARNED_VOCABULARY_HEADER)?;
+    writeln!(file, "# Learned from {} by `scriba learn`; run it again rather than editing this file", path.display())?;
+    for entry in &entries {
+        writeln!(file, "{}", entry)?;
+    }
+    file.flush()?;
+    
+    println!("📚 Learned {} terms from {} files, saved to {}", entries.len(), files, output.display());
+    Ok(())
+}
+
+/// Command sent to a running instance through its control socket
+enum ControlCommand {
+    SetProfile(String),
+}
+
+impl ControlCommand {
+    fn parse(line: &str) -> Option<Self> {
+        match line.trim().split_once(' ') {
+            Some(("profile", name)) => Some(ControlCommand::SetProfile(name.trim().to_string())),
+            _ => None,
+        }
+    }
+    
+    fn to_line(&self) -> String {
+        match self {
+            ControlCommand::SetProfile(name) => format!("profile {}\n", name),
+        }
+    }
+}
+
+/// Socket a running instance listens on for control commands, in the
+/// runtime directory when there is one
+fn control_socket_path(config_path: &Path) -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(|| config_path.to_path_buf())
+        .join("scriba.sock")
+}
+
+/// The control socket of this instance, removed when dropped so that no
+/// stale socket is left behind
+#[cfg_attr(not(unix), allow(dead_code))]
+struct ControlSocket {
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Listen for control commands, one per line, forwarding them to `commands`
+#[cfg(unix)]
+fn spawn_control_listener(config_path: &Path, commands: mpsc::UnboundedSender<ControlCommand>) -> Result<ControlSocket, Box<dyn std::error::Error>> {
+    use tokio::io::AsyncBufReadExt;
+    
+    let path = control_socket_path(config_path);
+    if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        return Err(format!("another instance is listening on {}", path.display()).into());
+    }
+    // Left behind by an instance that didn't exit cleanly
+    let _ = std::fs::remove_file(&path);
+    let listener = tokio::net::UnixListener::bind(&path)?;
+    let socket = ControlSocket { path };
+    
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Control socket error: {}", e);
+                    continue;
+                }
+            };
+            let commands = commands.clone();
+            tokio::spawn(async move {
+                let mut lines = tokio::io::BufReader::new(stream).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    match ControlCommand::parse(&line) {
+                        Some(command) => {
+                            let _ = commands.send(command);
+                        }
+                        None => warn!("Unknown control command: {}", line),
+                    }
+                }
+            });
+        }
+    });
+    
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn spawn_control_listener(_config_path: &Path, _commands: mpsc::UnboundedSender<ControlCommand>) -> Result<ControlSocket, Box<dyn std::error::Error>> {
+    Err("Control commands are only supported on Unix".into())
+}
+
+#[cfg(unix)]
+async fn send_control_command(config_path: &Path, command: &ControlCommand) -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::AsyncWriteExt;
+    
+    let path = control_socket_path(config_path);
+    let mut stream = tokio::net::UnixStream::connect(&path)
+        .await
+        .map_err(|e| format!("No running instance found at {}: {}", path.display(), e))?;
+    stream.write_all(command.to_line().as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn send_control_command(_config_path: &Path, _command: &ControlCommand) -> Result<(), Box<dyn std::error::Error>> {
+    Err("Control commands are only supported on Unix".into())
+}
+
+/// The focused window, as reported by the window system
+#[derive(Debug, Clone)]
+struct ActiveWindow {
+    /// Identifies the window for as long as it exists
+    id: String,
+    class: String,
+    title: String,
+}
+
+/// Source of the focused window, so that dictation can follow it
+trait WindowDetector: Send {
+    /// The focused window, or `None` when no window has focus
+    fn active_window(&mut self) -> Result<Option<ActiveWindow>, Box<dyn std::error::Error>>;
+}
+
+/// `[[window_rules]]` with their patterns compiled
+struct WindowRules {
+    rules: Vec<(Option<Regex>, Option<Regex>, WindowRule)>,
+}
+
+impl WindowRules {
+    fn new(rules: &[WindowRule], profiles: &Profiles) -> Result<Self, Box<dyn std::error::Error>> {
+        let compile = |pattern: &Option<String>| -> Result<Option<Regex>, Box<dyn std::error::Error>> {
+            pattern
+                .as_deref()
+                .map(|p| Regex::new(&format!("(?i){}", p)).map_err(|e| format!("Invalid window rule pattern '{}': {}", p, e).into()))
+                .transpose()
+        };
+        
+        let mut compiled = Vec::new();
+        for rule in rules {
+            if let Some(name) = rule.profile.as_deref().filter(|name| profiles.get(name).is_none()) {
+                return Err(format!("Unknown profile '{}' in window rule. Available profiles: {}", name, profiles.names().join(", ")).into());
+            }
+            compiled.push((compile(&rule.class)?, compile(&rule.title)?, rule.clone()));
+        }
+        Ok(WindowRules { rules: compiled })
+    }
+    
+    fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+    
+    fn find(&self, window: &ActiveWindow) -> Option<&WindowRule> {
+        self.rules
+            .iter()
+            .find(|(class, title, _)| {
+                class.as_ref().is_none_or(|class| class.is_match(&window.class))
+                    && title.as_ref().is_none_or(|title| title.is_match(&window.title))
+            })
+            .map(|(_, _, rule)| rule)
+    }
+}
+
+/// Ask `detector` for the focused window on a blocking thread, as the window
+/// system round trips would otherwise hold up the runtime
+async fn detect_window(detector: &mut Option<Box<dyn WindowDetector>>) -> Option<ActiveWindow> {
+    let mut taken = detector.take()?;
+    let joined = tokio::task::spawn_blocking(move || {
+        let window = taken.active_window().map_err(|e| e.to_string());
+        (taken, window)
+    })
+    .await;
+
+    let Ok((taken, window)) = joined else {
+        warn!("Window detection failed, disabling it");
+        return None;
+    };
+    *detector = Some(taken);
+    window.unwrap_or_else(|e| {
+        warn!("Failed to detect the focused window: {}", e);
+        None
+    })
+}
+
+/// Detector for the running window system: Sway or Hyprland IPC on
+/// Wayland, `_NET_ACTIVE_WINDOW` on X11
+#[cfg(target_os = "linux")]
+fn connect_window_detector() -> Option<Box<dyn WindowDetector>> {
+    if let Some(socket) = std::env::var_os("SWAYSOCK") {
+        return Some(Box::new(SwayDetector { socket: PathBuf::from(socket) }));
+    }
+    if let Some(signature) = std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE") {
+        let runtime_socket = dirs::runtime_dir().map(|dir| dir.join("hypr").join(&signature).join(".socket.sock"));
+        let socket = runtime_socket
+            .filter(|path| path.exists())
+            .unwrap_or_else(|| Path::new("/tmp/hypr").join(&signature).join(".socket.sock"));
+        return Some(Box::new(HyprlandDetector { socket }));
+    }
+    if std::env::var_os("DISPLAY").is_some() {
+        match X11Detector::connect() {
+            Ok(detector) => return Some(Box::new(detector)),
+            Err(e) => warn!("Failed to connect to the X server: {}", e),
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_window_detector() -> Option<Box<dyn WindowDetector>> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+struct X11Detector {
+    conn: x11rb::rust_connection::RustConnection,
+    root: u32,
+    net_active_window: u32,
+    net_wm_name: u32,
+    utf8_string: u32,
+}
+
+#[cfg(target_os = "linux")]
+impl X11Detector {
+    fn connect() -> Result<Self, Box<dyn std::error::Error>> {
+        use x11rb::connection::Connection;
+        use x11rb::protocol::xproto::ConnectionExt;
+        
+        let (conn, screen) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen].root;
+        let atom = |name: &[u8]| -> Result<u32, Box<dyn std::error::Error>> {
+            Ok(conn.intern_atom(false, name)?.reply()?.atom)
+        };
+        let net_active_window = atom(b"_NET_ACTIVE_WINDOW")?;
+        let net_wm_name = atom(b"_NET_WM_NAME")?;
+        let utf8_string = atom(b"UTF8_STRING")?;
+        
+        Ok(X11Detector { conn, root, net_active_window, net_wm_name, utf8_string })
+    }
+    
+    fn property(&self, window: u32, property: u32, kind: u32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        use x11rb::protocol::xproto::ConnectionExt;
+        
+        Ok(self.conn.get_property(false, window, property, kind, 0, 1024)?.reply()?.value)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl WindowDetector for X11Detector {
+    fn active_window(&mut self) -> Result<Option<ActiveWindow>, Box<dyn std::error::Error>> {
+        use x11rb::protocol::xproto::{AtomEnum, ConnectionExt};
+        
+        let reply = self.conn
+            .get_property(false, self.root, self.net_active_window, AtomEnum::WINDOW, 0, 1)?
+            .reply()?;
+        let Some(window) = reply.value32().and_then(|mut values| values.next()).filter(|&w| w != 0) else {
+            return Ok(None);
+        };
+        
+        // WM_CLASS holds the instance and class names, each NUL-terminated
+        let wm_class = self.property(window, AtomEnum::WM_CLASS.into(), AtomEnum::STRING.into())?;
+        let class = wm_class
+            .split(|&b| b == 0)
+            .nth(1)
+            .map(|class| String::from_utf8_lossy(class).into_owned())
+            .unwrap_or_default();
+        
+        let mut title = self.property(window, self.net_wm_name, self.utf8_string)?;
+        if title.is_empty() {
+            title = self.property(window, AtomEnum::WM_NAME.into(), AtomEnum::STRING.into())?;
+        }
+        
+        Ok(Some(ActiveWindow {
+            id: format!("{:#x}", window),
+            class,
+            title: String::from_utf8_lossy(&title).into_owned(),
+        }))
+    }
+}
+
+/// Focused window from Sway's IPC (`GET_TREE`)
+#[cfg(target_os = "linux")]
+struct SwayDetector {
+    socket: PathBuf,
+}
+
+#[cfg(target_os = "linux")]
+impl SwayDetector {
+    const GET_TREE: u32 = 4;
+    
+    fn find_focused(node: &serde_json::Value) -> Option<&serde_json::Value> {
+        if node["focused"].as_bool() == Some(true) {
+            return Some(node);
+        }
+        ["nodes", "floating_nodes"]
+            .iter()
+            .filter_map(|key| node[*key].as_array())
+            .flatten()
+            .find_map(Self::find_focused)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl WindowDetector for SwayDetector {
+    fn active_window(&mut self) -> Result<Option<ActiveWindow>, Box<dyn std::error::Error>> {
+        let mut stream = std::os::unix::net::UnixStream::connect(&self.socket)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        
+        // i3 IPC message: magic, payload length, message type
+        let mut request = b"i3-ipc".to_vec();
+        request.extend_from_slice(&0u32.to_ne_bytes());
+        request.extend_from_slice(&Self::GET_TREE.to_ne_bytes());
+        stream.write_all(&request)?;
+        
+        let mut header = [0u8; 14];
+        stream.read_exact(&mut header)?;
+        let length = u32::from_ne_bytes(header[6..10].try_into()?) as usize;
+        let mut payload = vec![0u8; length];
+        stream.read_exact(&mut payload)?;
+        
+        let tree: serde_json::Value = serde_json::from_slice(&payload)?;
+        Ok(Self::find_focused(&tree).map(|node| ActiveWindow {
+            id: node["id"].to_string(),
+            // Wayland clients have an app id, XWayland ones a window class
+            class: node["app_id"]
+                .as_str()
+                .or_else(|| node["window_properties"]["class"].as_str())
+                .unwrap_or_default()
+                .to_string(),
+            title: node["name"].as_str().unwrap_or_default().to_string(),
+        }))
+    }
+}
+
+/// Focused window from Hyprland's IPC (`activewindow`)
+#[cfg(target_os = "linux")]
+struct HyprlandDetector {
+    socket: PathBuf,
+}
+
+#[cfg(target_os = "linux")]
+impl WindowDetector for HyprlandDetector {
+    fn active_window(&mut self) -> Result<Option<ActiveWindow>, Box<dyn std::error::Error>> {
+        let mut stream = std::os::unix::net::UnixStream::connect(&self.socket)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        stream.write_all(b"j/activewindow")?;
+        
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        
+        let window: serde_json::Value = serde_json::from_slice(&response)?;
+        let Some(class) = window["class"].as_str() else {
+            // `{}` when no window has focus
+            return Ok(None);
+        };
+        Ok(Some(ActiveWindow {
+            id: window["address"].as_str().unwrap_or_default().to_string(),
+            class: class.to_string(),
+            title: window["title"].as_str().unwrap_or_default().to_string(),
+        }))
+    }
+}
+
+/// Play mono `samples` on the default output device, resampling to the
+/// device rate and copying to every channel.
+async fn play_samples(samples: &[f32], sample_rate: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .ok_or("No output device available")?;
+    let config: cpal::StreamConfig = device.default_output_config()?.into();
+    
+    let channels = config.channels.max(1) as usize;
+    let ratio = sample_rate as f64 / config.sample_rate.0 as f64;
+    let frames = (samples.len() as f64 / ratio) as usize;
+    let resampled: Vec<f32> = (0..frames)
+        .map(|i| samples[((i as f64 * ratio) as usize).min(samples.len() - 1)])
+        .collect();
+    
+    let mut position = 0;
+    let stream = device.build_output_stream(
+        &config,
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            for frame in data.chunks_mut(channels) {
+                let sample = resampled.get(position).copied().unwrap_or(0.0);
+                frame.fill(sample);
+                position += 1;
+            }
+        },
+        |err| {
+            error!("Audio playback error: {}", err);
+        },
+        None,
+    )?;
+    
+    stream.play()?;
+    tokio::time::sleep(Duration::from_secs_f64(frames as f64 / config.sample_rate.0 as f64) + Duration::from_millis(200)).await;
+    
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Cli::parse();
+    
+    // Setup logging
+    let log_level = if args.debug { "debug" } else { "info" };
+    tracing_subscriber::fmt()
+        .with_env_filter(format!("scriba={}", log_level))
+        .init();
+    
+    // Configuration directory in the user's home
+    let config_path = config_dir()
+        .ok_or("Could not find config directory")?
+        .join("scriba");
+    create_dir_all(&config_path)?;
+
+    let models_dir = config_path.join("models");
+    create_dir_all(&models_dir)?;
+
+    let config = Config::load(&config_path)?;
+
+    match &args.command {
+        Some(Command::MicTest { seconds, record, playback, transcribe }) => {
+            return run_mic_test(&args, &config, &config_path, &models_dir, *seconds, record.as_deref(), *playback, *transcribe).await;
+        }
+        Some(Command::EnrollSpeaker { name, seconds }) => {
+            return run_enroll_speaker(&args, &config, &config_path, &models_dir, name, *seconds).await;
+        }
+        Some(Command::Learn { path, output, min_count, max_entries }) => {
+            return run_learn(&config_path, path, output.as_deref(), *min_count, *max_entries);
+        }
+        Some(Command::Profile { name }) => {
+            send_control_command(&config_path, &ControlCommand::SetProfile(name.clone())).await?;
+            println!("🔀 Asked the running instance to switch to the {} profile", name);
+            return Ok(());
+        }
+        None => {}
+    }
+
+    info!("Starting Scriba...");
+    let mut profiles = Profiles::load(&config, args.profile.as_deref())?;
+    let window_rules = WindowRules::new(&config.window_rules, &profiles)?;
+    let (mut model, language) = load_model(&args, &config, &models_dir).await?;
+    let disfluencies = DisfluencyFilter::new(&config.cleanup, &language);
+    let vocabulary = Vocabulary::load(&config_path, &config.vocabulary)?;
+    if !vocabulary.is_empty() {
+        let known = vocabulary.known_phrases(&mut model).len();
+        info!("Vocabulary: {} terms, {} known to the model", vocabulary.entries.len(), known);
+    }
+    info!("Sample rate: {}", args.sample_rate);
+    info!("Confidence threshold: {}", args.confidence_threshold);
+    
+    // Bounded buffers between the audio callbacks and the recognizer
+    let buffer_samples = (args.sample_rate as u64 * config.audio.buffer_ms / 1000) as usize;
+    let mut rings = Vec::new();
+    
+    if let Some(path) = &args.input {
+        if !matches!(config.audio.source, AudioSource::Mic) {
+            warn!("Reading audio from --input; the configured audio source {:?} is ignored", config.audio.source);
+        }
+        // Raw input can be read faster than real time, so wait for the
+        // recognizer instead of dropping audio
+        let ring = Arc::new(AudioRingBuffer::new(buffer_samples, OverflowPolicy::Block));
+        setup_raw_input(path, args.input_format, args.input_channels, ring.clone())?;
+        rings.push(ring);
+    } else {
+        let source = args.source.unwrap_or(config.audio.source);
+        info!("Audio source: {:?}", source);
+        
+        let mut capture_devices = Vec::new();
+        if matches!(source, AudioSource::Mic | AudioSource::Both) {
+            capture_devices.push(CaptureDevice::Microphone(config.audio.device.clone()));
+        }
+        if matches!(source, AudioSource::System | AudioSource::Both) {
+            capture_devices.push(CaptureDevice::SystemAudio(config.audio.system_device.clone()));
+        }
+        
+        // Setup audio streams
+        for device in capture_devices {
+            let ring = Arc::new(AudioRingBuffer::new(buffer_samples, config.audio.overflow));
+            setup_audio_stream(args.sample_rate, device, ring.clone())?;
+            rings.push(ring);
+        }
+    }
+    let mut input = AudioMixer::new(rings);
+    
+    // Create audio processor
+    let (result_tx, mut result_rx) = mpsc::unbounded_channel::<TranscriptionResult>();
+    let grammar = config.vocabulary.command_only.then(|| vocabulary.known_phrases(&mut model));
+    let mut processor = AudioProcessor::new(&model, args.sample_rate as f32, grammar.as_deref())?;
+    // Kept alive for as long as the recognizer uses it
+    let _speaker_model = if config.speakers.enabled {
+        let speaker_model = load_speaker_model(&config, &models_dir).await?;
+        let enrolled = EnrolledSpeakers::load(&config_path)?;
+        info!("Speaker identification enabled ({} enrolled speakers)", enrolled.speakers.len());
+        processor.enable_speakers(&speaker_model, Some(SpeakerRegistry::new(enrolled, config.speakers.max_distance)));
+        Some(speaker_model)
+    } else {
+        None
+    };
+    
+    // Spawn the decoder thread
+    let mut filters = AudioFilterChain::new(config.dsp.clone(), args.sample_rate);
+    let mut vad = VoiceActivityDetector::new(config.vad.clone(), args.sample_rate);
+    let mut recorder = match &args.record {
+        Some(dir) => Some(SessionRecorder::new(dir, args.record_mode, args.sample_rate)?),
+        None => None,
+    };
+    let sample_rate = args.sample_rate;
+    let chunk_size = config.audio.chunk_size.clamp(1, buffer_samples.max(1));
+    let processor_handle = std::thread::Builder::new().name("scriba-decoder".to_string()).spawn(move || {
+        let mut chunk = vec![0.0f32; chunk_size];
+        let mut overruns = OverrunReporter::new();
+        
+        loop {
+            overruns.check(&input, sample_rate);
+            
+            let mut finished = false;
+            if !input.read_chunk(&mut chunk) {
+                if !input.is_finished() {
+                    std::thread::sleep(AUDIO_POLL_INTERVAL);
+                    continue;
+                }
+                finished = true;
+            }
+            
+            let result = if finished {
+                // End of input: flush the last utterance
+                Ok(processor.finish_utterance())
+            } else {
+                if filters.is_enabled() {
+                    filters.process(&mut chunk);
+                }
+                
+                match vad.process(&chunk) {
+                    VoiceActivity::Speech => {
+                        let mut samples = vad.take_pre_roll();
+                        samples.extend_from_slice(&chunk);
+                        let i16_chunk = convert_f32_to_i16(&samples);
+                        if let Some(recorder) = &mut recorder {
+                            if let Err(e) = recorder.write(&i16_chunk) {
+                                error!("Failed to record audio: {}", e);
+                            }
+                        }
+                        processor.process_audio(&i16_chunk)
+                    }
+                    VoiceActivity::Silence => Ok(None),
+                    VoiceActivity::EndOfUtterance => Ok(processor.finish_utterance()),
+                }
+            };
+            
+            match result {
+                Ok(Some(result)) => {
+                    if let Some(recorder) = recorder.as_mut().filter(|_| result.is_final) {
+                        if let Err(e) = recorder.finish_utterance(&result) {
+                            error!("Failed to record transcript: {}", e);
+                        }
+                    }
+                    if let Err(e) = result_tx.send(result) {
+                        error!("Failed to send transcription result: {}", e);
+                        break;
+                    }
+                }
+                Ok(None) => {}, // No transcription result
+                Err(e) => {
+                    error!("Audio processing error: {}", e);
+                }
+            }
+            
+            if processor.take_discarded_final() {
+                if let Some(recorder) = &mut recorder {
+                    if let Err(e) = recorder.discard_utterance() {
+                        error!("Failed to discard recorded audio: {}", e);
+                    }
+                }
+            }
+            
+            if finished {
+                break;
+            }
+        }
+        
+        input.close();
+    })?;
+    
+    // Create text typer
+    let mut typer = if args.no_typing {
+        None
+    } else {
+        match TextTyper::new() {
+            Ok(t) => Some(t),
+            Err(e) => {
+                error!("Failed to create text typer: {}. Running in no-typing mode.", e);
+                None
+            }
+        }
+    };
+    
+    println!("🎙️  Scriba is running!");
+    if typer.is_some() {
+        println!("📝 Text will be typed in the currently focused input field.");
+        println!("🛑 Press Ctrl+C to stop.");
+    } else {
+        println!("📄 Typing is disabled. Transcriptions will only be printed.");
+        println!("🛑 Press Ctrl+C to stop.");
+    }
+    println!();
+    
+    // Commands from `scriba profile`
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel::<ControlCommand>();
+    let _control_socket = match spawn_control_listener(&config_path, control_tx) {
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            warn!("Could not listen for control commands: {}", e);
+            None
+        }
+    };
+    
+    // Process transcription results
+    let mode = args.mode.or(config.mode).unwrap_or(DictationMode::Code);
+    info!("Dictation mode: {:?}", mode);
+    info!("Language profile: {}", profiles.active().name);
+    // Also used without window rules when typing, to start afresh in every
+    // window; nothing is typed with --no-typing, so it isn't needed then
+    let mut window_detector = if window_rules.is_empty() && args.no_typing {
+        None
+    } else {
+        connect_window_detector()
+    };
+    if window_detector.is_none() && !window_rules.is_empty() {
+        warn!("Window rules are configured but the focused window can't be detected on this system");
+    }
+    let mut output = OutputContext::new();
+    let mut last_window_id = None;
+    let mut interrupted = false;
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        let result = tokio::select! {
+            _ = &mut ctrl_c => {
+                interrupted = true;
+                break;
+            }
+            Some(command) = control_rx.recv() => {
+                match command {
+                    ControlCommand::SetProfile(name) => {
+                        if profiles.select(&name) {
+                            info!("🔀 Switched to the {} profile", profiles.active().name);
+                        } else {
+                            warn!("Unknown profile '{}'. Available profiles: {}", name, profiles.names().join(", "));
+                        }
+                    }
+                }
+                continue;
+            }
+            result = result_rx.recv() => match result {
+                Some(result) => result,
+                None => break,
+            },
+        };
+        
+        if result.is_final && result.confidence >= args.confidence_threshold {
+            if profiles.handle_voice_command(&result.text) {
+                info!("🔀 Switched to the {} profile", profiles.active().name);
+                continue;
+            }
+            
+            // Settings of the window the text goes to. Text typed in another
+            // window doesn't continue the previous output.
+            let window = detect_window(&mut window_detector).await;
+            let window_id = window.as_ref().map(|window| window.id.clone());
+            if window_id != last_window_id {
+                output = OutputContext::new();
+                last_window_id = window_id;
+            }
+            let rule = window.as_ref().and_then(|window| window_rules.find(window));
+            let mode = rule.and_then(|rule| rule.mode).unwrap_or(mode);
+            let profile = rule
+                .and_then(|rule| rule.profile.as_deref())
+                .and_then(|name| profiles.get(name))
+                .unwrap_or(profiles.active());
+            let typing = rule.and_then(|rule| rule.typing).unwrap_or(true);
+            
+            let enhanced_text = enhance_transcription(&result.text, mode, profile, &vocabulary, &disfluencies, output.at_sentence_start());
+            
+            let printed_text = enhanced_text.replace(CURSOR_MARKER, "");
+            match &result.speaker {
+                Some(speaker) => info!("📝 [{}] Transcription (confidence: {:.2}): {}", speaker.label, result.confidence, printed_text),
+                None => info!("📝 Transcription (confidence: {:.2}): {}", result.confidence, printed_text),
+            }
+            
+            if let Some(typer) = typer.as_mut().filter(|_| typing) {
+                typer.type_text(&TranscriptionResult {
+                    text: output.join(&enhanced_text),
+                    confidence: result.confidence,
+                    is_final: result.is_final,
+                    speaker: result.speaker,
+                }, args.confidence_threshold);
+            }
+        } else if args.debug && !result.is_final {
+            info!("🔄 Partial: {}", result.text);
+        }
+    }
+
+    // The decoder only stops by itself at the end of raw input
+    if !interrupted {
+        processor_handle.join().map_err(|_| "Decoder thread panicked")?;
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    /// Scratch directory under the system temp dir, removed before use
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("scriba-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Minimal model archive containing a single `conf/model.conf`
+    fn model_archive() -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("vosk-model-test/conf/model.conf", zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(b"--sample-frequency=16000\n").unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// HTTP server answering the first `failures` requests with a 500 and
+    /// every later one with `body`. Returns its address and the request lines
+    /// it received.
+    fn serve(failures: usize, body: Vec<u8>) -> (std::net::SocketAddr, Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = requests.clone();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut header = String::new();
+                    if reader.read_line(&mut header).unwrap() == 0 || header == "\r\n" {
+                        break;
+                    }
+                }
+
+                let served = {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push(request_line.trim_end().to_string());
+                    seen.len() > failures
+                };
+                if served {
+                    write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+                    stream.write_all(&body).unwrap();
+                } else {
+                    stream.write_all(b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+                }
+            }
+        });
+
+        (addr, requests)
+    }
+
+    fn test_model() -> ModelInfo {
+        ModelInfo {
+            name: "Test Model".to_string(),
+            url: format!("{}/vosk-model-test.zip", MODELS_BASE_URL),
+            size: "1KB",
+            description: "Test model",
+            language: "English",
+        }
+    }
+
+    #[tokio::test]
+    async fn download_retries_from_mirror() {
+        let (addr, requests) = serve(2, model_archive());
+        let dest = scratch_dir("mirror");
+        let download = DownloadConfig {
+            mirror: Some(format!("http://{}/mirror/", addr)),
+            retry_backoff_ms: 1,
+            ..DownloadConfig::default()
+        };
+
+        download_and_extract_model(&test_model(), &dest, &download).await.unwrap();
+
+        assert_eq!(*requests.lock().unwrap(), vec!["GET /mirror/vosk-model-test.zip HTTP/1.1"; 3]);
+        assert!(dest.join("vosk-model-test/conf/model.conf").is_file());
+        assert!(!dest.join("model.zip").exists());
+        std::fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[tokio::test]
+    async fn download_goes_through_proxy() {
+        let (addr, requests) = serve(0, model_archive());
+        let dest = scratch_dir("proxy");
+        let download = DownloadConfig {
+            mirror: Some("http://models.example.invalid".to_string()),
+            proxy: Some(format!("http://{}", addr)),
+            ..DownloadConfig::default()
+        };
+
+        download_and_extract_model(&test_model(), &dest, &download).await.unwrap();
+
+        assert_eq!(*requests.lock().unwrap(), vec!["GET http://models.example.invalid/vosk-model-test.zip HTTP/1.1"]);
+        assert!(dest.join("vosk-model-test/conf/model.conf").is_file());
+        std::fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[tokio::test]
+    async fn download_gives_up_after_retries() {
+        let (addr, requests) = serve(usize::MAX, Vec::new());
+        let dest = scratch_dir("retries");
+        let download = DownloadConfig {
+            mirror: Some(format!("http://{}", addr)),
+            retries: 1,
+            retry_backoff_ms: 1,
+            ..DownloadConfig::default()
+        };
+
+        let error = download_and_extract_model(&test_model(), &dest, &download).await.unwrap_err();
+
+        assert!(error.to_string().starts_with("Download failed after 2 attempts"), "{}", error);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        std::fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn vad_feeds_pre_roll_when_speech_starts() {
+        let config = VadConfig { enabled: true, pre_roll_ms: 250, ..VadConfig::default() };
+        let mut vad = VoiceActivityDetector::new(config, 16000);
+        let silence = vec![0.0f32; 1600];
+        let speech = vec![0.5f32; 1600];
+
+        for _ in 0..5 {
+            assert_eq!(vad.process(&silence), VoiceActivity::Silence);
+        }
+        assert_eq!(vad.process(&speech), VoiceActivity::Speech);
+        assert_eq!(vad.take_pre_roll().len(), 4000);
+        assert!(vad.take_pre_roll().is_empty());
+    }
+
+
+    fn final_result(text: &str) -> TranscriptionResult {
+        TranscriptionResult {
+            text: text.to_string(),
+            confidence: 1.0,
+            is_final: true,
+            speaker: None,
+        }
+    }
+
+    #[test]
+    fn recorder_skips_audio_of_empty_utterances() {
+        let dir = scratch_dir("record");
+        let mut recorder = SessionRecorder::new(&dir, RecordMode::Session, 16000).unwrap();
+        recorder.write(&[0; 16000]).unwrap();
+        recorder.discard_utterance().unwrap();
+        recorder.write(&[0; 8000]).unwrap();
+        recorder.finish_utterance(&final_result("hello")).unwrap();
+
+        let transcript = std::fs::read_to_string(dir.join(format!("{}.jsonl", recorder.session))).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(transcript.trim()).unwrap();
+        assert_eq!(entry["start"], 1.0);
+        assert_eq!(entry["end"], 1.5);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recorder_removes_files_of_empty_utterances() {
+        let dir = scratch_dir("record-utterance");
+        let mut recorder = SessionRecorder::new(&dir, RecordMode::Utterance, 16000).unwrap();
+        recorder.write(&[0; 16000]).unwrap();
+        recorder.discard_utterance().unwrap();
+        assert!(!dir.join(format!("{}-0001.wav", recorder.session)).exists());
+
+        recorder.write(&[0; 8000]).unwrap();
+        recorder.finish_utterance(&final_result("hello")).unwrap();
+        let reader = hound::WavReader::open(dir.join(format!("{}-0001.wav", recorder.session))).unwrap();
+        assert_eq!(reader.duration(), 8000);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn blocking_push_gives_up_on_stalled_reader() {
+        let ring = AudioRingBuffer::new(4, OverflowPolicy::Block);
+        ring.push(&[1.0; 4]);
+
+        let started = Instant::now();
+        ring.push(&[2.0; 2]);
+        assert!(started.elapsed() >= MAX_BLOCK_WAIT);
+        assert_eq!(ring.dropped_samples.load(Ordering::Relaxed), 2);
+
+        let mut out = [0.0; 4];
+        assert!(ring.read_chunk(&mut out));
+        assert_eq!(out, [1.0, 1.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn blocking_push_drops_once_closed() {
+        let ring = AudioRingBuffer::new(4, OverflowPolicy::Block);
+        ring.push(&[1.0; 4]);
+        ring.close();
+
+        let started = Instant::now();
+        ring.push(&[2.0; 4]);
+        assert!(started.elapsed() < MAX_BLOCK_WAIT);
+        assert_eq!(ring.dropped_samples.load(Ordering::Relaxed), 4);
+    }
+
+
+    #[test]
+    fn mixer_keeps_samples_of_a_source_that_is_ahead() {
+        let primary = Arc::new(AudioRingBuffer::new(16, OverflowPolicy::DropOldest));
+        let other = Arc::new(AudioRingBuffer::new(16, OverflowPolicy::DropOldest));
+        let mut mixer = AudioMixer::new(vec![primary.clone(), other.clone()]);
+        let mut out = [0.0; 4];
+
+        primary.push(&[0.1; 4]);
+        other.push(&[0.2, 0.2, 0.2, 0.2, 0.3, 0.3]);
+        assert!(mixer.read_chunk(&mut out));
+        assert!(out.iter().all(|&sample| (sample - 0.3).abs() < 1e-6));
+
+        primary.push(&[0.1; 4]);
+        assert!(mixer.read_chunk(&mut out));
+        assert!(out[..2].iter().all(|&sample| (sample - 0.4).abs() < 1e-6));
+        assert!(out[2..].iter().all(|&sample| (sample - 0.1).abs() < 1e-6));
+    }
+
+
+    /// Reader handing out a few bytes at a time, like a pipe under load
+    struct Trickle(std::io::Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    /// Pipe `bytes` through the raw input reader and return what the
+    /// recognizer would receive
+    fn read_raw_input(bytes: Vec<u8>, format: InputFormat, channels: u16) -> Vec<f32> {
+        let ring = Arc::new(AudioRingBuffer::new(1024, OverflowPolicy::Block));
+        spawn_raw_reader(Box::new(Trickle(std::io::Cursor::new(bytes))), format, channels, ring.clone()).unwrap();
+        let mut input = AudioMixer::new(vec![ring.clone()]);
+
+        while !ring.is_closed() {
+            std::thread::sleep(AUDIO_POLL_INTERVAL);
+        }
+        let mut samples = vec![0.0; ring.available()];
+        assert!(input.read_chunk(&mut samples));
+        assert!(input.is_finished());
+        samples
+    }
+
+    #[test]
+    fn raw_input_decodes_s16le() {
+        let bytes = [0i16, 16384, -16384, i16::MIN].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        assert_eq!(read_raw_input(bytes, InputFormat::S16le, 1), vec![0.0, 0.5, -0.5, -1.0]);
+    }
+
+    #[test]
+    fn raw_input_downmixes_f32le() {
+        let bytes = [0.5f32, 0.25, -1.0, 0.0].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        assert_eq!(read_raw_input(bytes, InputFormat::F32le, 2), vec![0.375, -0.5]);
+    }
+
+    #[test]
+    fn raw_input_ignores_trailing_partial_frame() {
+        let mut bytes: Vec<u8> = [0.5f32, 0.5].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        bytes.extend([0, 0, 0]);
+        assert_eq!(read_raw_input(bytes, InputFormat::F32le, 2), vec![0.5]);
+    }
+
+
+    #[test]
+    fn speaker_model_is_not_a_speech_model() {
+        assert!(find_model_info(SPEAKER_MODEL.id()).is_none());
+        assert!(find_any_model_info(SPEAKER_MODEL.id()).is_some());
+        assert!(find_model_info(RECOMMENDED_MODEL_ID).is_some());
+    }
+
+
+    #[test]
+    fn prose_quotes() {
+        assert_eq!(format_prose("he said quote hello close quote", true), "He said \"hello\"");
+        assert_eq!(format_prose("open quote yes end quote she said", true), "\"Yes\" she said");
+    }
+
+
+    fn generic_profile() -> LanguageProfile {
+        LanguageProfile::new("generic", BUILTIN_PROFILES.first(), None, &[]).unwrap()
+    }
+
+    #[test]
+    fn code_dictation_converts_ambiguous_words_in_context() {
+        let profile = generic_profile();
+        let cases = [
+            ("this one works", "this one works"),
+            ("three times four", "3 * 4"),
+            ("three times", "three times"),
+            ("x equals one", "x = 1"),
+            ("number one", "1"),
+            ("equals minus one", "= -1"),
+            ("symbol colon", ":"),
+            ("colon colon", "::"),
+            ("self dot name", "self.name"),
+            ("self dot user dot name", "self.user.name"),
+            ("foo colon bar", "foo colon bar"),
+            ("polka dot dress", "polka dot dress"),
+            ("connect the dot to the line", "connect the dot to the line"),
+            ("note colon this is important", "note colon this is important"),
+            ("it costs three times more", "it costs three times more"),
+            ("the price minus the discount", "the price minus the discount"),
+            ("one of them is done", "one of them is done"),
+            ("she is the one", "she is the one"),
+            ("two minus one", "2 - 1"),
+            ("ten minutes", "ten minutes"),
+            ("a hundred times", "a hundred times"),
+            ("x equals ten", "x = 10"),
+            ("number twelve", "12"),
+            ("twenty five", "25"),
+            ("version two point five", "version 2.5"),
+        ];
+        for (spoken, written) in cases {
+            assert_eq!(format_code_dictation(spoken, &profile), written, "{:?}", spoken);
+        }
+    }
+
+
+    /// Reports a fixed sequence of focused windows
+    struct FakeDetector(VecDeque<ActiveWindow>);
+
+    impl WindowDetector for FakeDetector {
+        fn active_window(&mut self) -> Result<Option<ActiveWindow>, Box<dyn std::error::Error>> {
+            Ok(self.0.pop_front())
+        }
+    }
+
+    fn window(id: &str, class: &str, title: &str) -> ActiveWindow {
+        ActiveWindow { id: id.to_string(), class: class.to_string(), title: title.to_string() }
+    }
+
+    #[tokio::test]
+    async fn window_rules_follow_the_detected_window() {
+        let config: Config = toml::from_str(r#"
+            [[window_rules]]
+            class = "^code$"
+            profile = "python"
+
+            [[window_rules]]
+            title = "slack"
+            mode = "prose"
+            typing = false
+        "#).unwrap();
+        let profiles = Profiles::load(&config, None).unwrap();
+        let rules = WindowRules::new(&config.window_rules, &profiles).unwrap();
+        let mut detector: Option<Box<dyn WindowDetector>> = Some(Box::new(FakeDetector(VecDeque::from([
+            window("1", "Code", "main.py"),
+            window("2", "firefox", "Slack | general"),
+            window("3", "kitty", "~"),
+        ]))));
+
+        let code = detect_window(&mut detector).await.unwrap();
+        assert_eq!(rules.find(&code).and_then(|rule| rule.profile.as_deref()), Some("python"));
+
+        let slack = rules.find(&detect_window(&mut detector).await.unwrap()).unwrap();
+        assert_eq!(slack.mode, Some(DictationMode::Prose));
+        assert_eq!(slack.typing, Some(false));
+
+        assert!(rules.find(&detect_window(&mut detector).await.unwrap()).is_none());
+        assert!(detect_window(&mut detector).await.is_none());
+        assert!(detector.is_some());
+    }
+
+    #[test]
+    fn window_rules_reject_unknown_profiles() {
+        let config: Config = toml::from_str("[[window_rules]]\nclass = \"code\"\nprofile = \"cobol\"").unwrap();
+        let profiles = Profiles::load(&config, None).unwrap();
+        assert!(WindowRules::new(&config.window_rules, &profiles).is_err());
+    }
+
+
+    fn vocabulary(contents: &str) -> Vocabulary {
+        let mut vocabulary = Vocabulary { entries: Vec::new(), threshold: 0.85, max_words: 0 };
+        vocabulary.add_entries(contents);
+        vocabulary
+    }
+
+    fn correct(vocabulary: &Vocabulary, text: &str) -> String {
+        let mut escaped = Vec::new();
+        let corrected = vocabulary.correct(text, DictationMode::Code, &mut escaped);
+        restore_escapes(&corrected, &escaped)
+    }
+
+    #[test]
+    fn vocabulary_prefers_windows_matching_the_spoken_form() {
+        let vocabulary = vocabulary("tokio\nuser service = UserService\nserde\n");
+        assert_eq!(correct(&vocabulary, "tokio i"), "tokio i");
+        assert_eq!(correct(&vocabulary, "use tokio i said"), "use tokio i said");
+        assert_eq!(correct(&vocabulary, "user service a"), "UserService a");
+        assert_eq!(correct(&vocabulary, "add sir day"), "add serde");
+    }
+
+    #[test]
+    fn vocabulary_keeps_words_that_only_share_consonants() {
+        let vocabulary = vocabulary("tokio\n");
+        assert_eq!(correct(&vocabulary, "taco tuesday"), "taco tuesday");
+    }
+
+    #[test]
+    fn unknown_words_are_dropped() {
+        assert_eq!(without_unknown_words("[unk] open tokio [unk]"), "open tokio");
+        assert_eq!(without_unknown_words("[unk]"), "");
+    }
+
+
+    #[test]
+    fn identifiers_split_into_spoken_words() {
+        for (identifier, spoken) in [
+            ("get_user_by_id", "get user by id"),
+            ("HttpClient", "http client"),
+            ("HTTPClient", "http client"),
+            ("XMLHttpRequest", "xml http request"),
+            ("parseJSON", "parse json"),
+            ("MAX_RETRIES", "max retries"),
+            ("utf8_decode", "utf 8 decode"),
+        ] {
+            assert_eq!(identifier_parts(identifier).join(" "), spoken, "{}", identifier);
+        }
+    }
+
+    #[test]
+    fn learned_vocabulary_only_applies_in_code() {
+        let vocabulary = vocabulary(&format!("{}\nfile name = file_name\n", LEARNED_VOCABULARY_HEADER));
+        let mut escaped = Vec::new();
+        assert_eq!(vocabulary.correct("the file name is wrong", DictationMode::Prose, &mut escaped), "the file name is wrong");
+        assert_eq!(correct(&vocabulary, "open file name"), "open file_name");
+    }
+
+    #[test]
+    fn learned_vocabulary_paths_differ_by_hash() {
+        assert_ne!(path_hash(Path::new("/work/app")), path_hash(Path::new("/home/app")));
+        assert_eq!(path_hash(Path::new("")), 0x811c9dc5);
+    }
+
+
+    fn clean(text: &str) -> String {
+        let disfluencies = DisfluencyFilter::new(&CleanupConfig::default(), "en");
+        let (text, escaped) = protect_escapes(text);
+        restore_escapes(&disfluencies.clean(&text, DictationMode::Prose), &escaped)
+    }
+
+    #[test]
+    fn fillers_are_removed() {
+        assert_eq!(clean("um so uh we hmm ship it"), "so we ship it");
+        assert_eq!(clean("say literal um"), "say um");
+
+        let mut config = CleanupConfig::default();
+        config.fillers.insert("en".to_string(), vec!["You know".to_string()]);
+        let disfluencies = DisfluencyFilter::new(&config, "en");
+        assert_eq!(disfluencies.clean("it is you know fine you", DictationMode::Prose), "it is fine you");
+    }
+
+    #[test]
+    fn repeated_phrases_are_collapsed() {
+        assert_eq!(clean("i want to i want to go"), "i want to go");
+        assert_eq!(clean("we can we can"), "we can");
+        assert_eq!(clean("he said that that was fine"), "he said that that was fine");
+        assert_eq!(clean("she had had enough"), "she had had enough");
+        assert_eq!(clean("back in twenty twenty"), "back in twenty twenty");
+        assert_eq!(clean("ten ten ten ten"), "ten ten ten ten");
+        assert_eq!(clean("one two one two"), "one two one two");
+
+        let disfluencies = DisfluencyFilter::new(&CleanupConfig::default(), "en");
+        assert_eq!(disfluencies.clean("close paren close paren", DictationMode::Code), "close paren close paren");
+    }
+
+
+    #[test]
+    fn extraction_skips_entries_outside_the_destination() {
+        let dir = scratch_dir("zip-slip");
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for name in ["../escaped.txt", "vosk-model-test/conf/model.conf"] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(b"data").unwrap();
+        }
+        let archive = dir.join("model.zip");
+        std::fs::write(&archive, zip.finish().unwrap().into_inner()).unwrap();
+
+        let dest = dir.join("models");
+        create_dir_all(&dest).unwrap();
+        extract_archive(&archive, &dest).unwrap();
+        assert!(dest.join("vosk-model-test/conf/model.conf").exists());
+        assert!(!dir.join("escaped.txt").exists());
+    }
+
+
+    fn sine(amplitude: f32, samples: usize) -> Vec<f32> {
+        (0..samples).map(|i| amplitude * (i as f32 * 2.0 * std::f32::consts::PI * 440.0 / 16000.0).sin()).collect()
+    }
+
+    #[test]
+    fn high_pass_removes_dc_offset() {
+        let mut filter = AudioFilterChain::new(DspConfig { high_pass: true, ..DspConfig::default() }, 16000);
+        let mut samples: Vec<f32> = sine(0.1, 16000).iter().map(|s| s + 0.5).collect();
+        filter.process(&mut samples);
+        let tail = &samples[samples.len() - 1600..];
+        let mean = tail.iter().sum::<f32>() / tail.len() as f32;
+        assert!(mean.abs() < 0.01, "{}", mean);
+    }
+
+    #[test]
+    fn noise_gate_mutes_quiet_blocks() {
+        let config = DspConfig { noise_gate: true, noise_gate_threshold_db: -40.0, ..DspConfig::default() };
+        let mut filter = AudioFilterChain::new(config, 16000);
+        // The first block ramps the gain down, the next ones are silent
+        let mut quiet = sine(0.001, 1600);
+        filter.process(&mut quiet);
+        assert!(quiet[160..].iter().all(|&s| s == 0.0));
+
+        let mut loud = sine(0.5, 1600);
+        filter.process(&mut loud);
+        assert_eq!(&loud[160..], &sine(0.5, 1600)[160..]);
+    }
+
+    #[test]
+    fn agc_converges_to_its_target() {
+        let config = DspConfig { agc: true, agc_target_db: -20.0, ..DspConfig::default() };
+        let mut filter = AudioFilterChain::new(config, 16000);
+        let mut samples = sine(0.01, 16000 * 3);
+        filter.process(&mut samples);
+        let level = rms_db(&samples[samples.len() - 1600..]);
+        assert!((level + 20.0).abs() < 1.0, "{}", level);
+    }
+
+    #[test]
+    fn amplified_audio_never_overflows_i16() {
+        let config = DspConfig { agc: true, agc_target_db: 0.0, agc_max_gain_db: 30.0, ..DspConfig::default() };
+        let mut filter = AudioFilterChain::new(config, 16000);
+        let mut samples = sine(0.9, 16000);
+        filter.process(&mut samples);
+        assert!(samples.iter().all(|s| (-1.0..=1.0).contains(s)));
+
+        assert_eq!(convert_f32_to_i16(&[2.0, -3.0, 1.0, -1.0, 0.0]), vec![32767, -32767, 32767, -32767, 0]);
+    }
+
+
+    #[test]
+    fn code_symbols_are_joined_by_spacing_rules() {
+        let rules = SpacingRules::default();
+        for (tokens, code) in [
+            ("foo ( bar , 1 )", "foo(bar, 1)"),
+            ("a + b", "a + b"),
+            ("x = = y", "x == y"),
+            ("x = - 1 ;", "x = -1;"),
+            ("items [ 0 ] . len ( )", "items[0].len()"),
+            ("if ( ready )", "if (ready)"),
+            ("std : : io", "std::io"),
+            ("map = { key : 1 }", "map = {key: 1}"),
+        ] {
+            assert_eq!(format_code(tokens, &rules), code, "{}", tokens);
+        }
+    }
+
+    #[test]
+    fn profiles_choose_the_keywords_spaced_before_parens() {
+        let config: Config = toml::from_str(
+            "[profiles.python.spacing]\nkeywords_before_paren = [\"elif\"]\ninside_braces = true",
+        )
+        .unwrap();
+        let profiles = Profiles::load(&config, None).unwrap();
+        let python = &profiles.get("python").unwrap().spacing;
+        assert_eq!(format_code("elif ( ready )", python), "elif (ready)");
+        assert_eq!(format_code("if ( ready )", python), "if(ready)");
+        assert_eq!(format_code("x = { }", python), "x = {}");
+        assert_eq!(format_code("x = { 1 }", python), "x = { 1 }");
+
+        let rust = &profiles.get("rust").unwrap().spacing;
+        assert_eq!(format_code("if ( ready )", rust), "if (ready)");
+    }
+
+
+    #[test]
+    fn vocabulary_terms_start_member_access() {
+        let vocabulary = vocabulary("tokio\n");
+        let disfluencies = DisfluencyFilter::new(&CleanupConfig::default(), "en");
+        let text = enhance_transcription("tokio dot spawn", DictationMode::Code, &generic_profile(), &vocabulary, &disfluencies, true);
+        assert_eq!(text, "tokio.spawn");
+    }
+
+
+    fn dictate(text: &str, mode: DictationMode) -> String {
+        let disfluencies = DisfluencyFilter::new(&CleanupConfig::default(), "en");
+        enhance_transcription(text, mode, &generic_profile(), &vocabulary(""), &disfluencies, true)
+    }
+
+    #[test]
+    fn literal_words_are_typed_as_spoken() {
+        assert_eq!(dictate("literal comma", DictationMode::Code), "comma");
+        assert_eq!(dictate("x equals literal null", DictationMode::Code), "x = null");
+        assert_eq!(dictate("foo comma literal comma", DictationMode::Code), "foo, comma");
+        assert_eq!(dictate("literal one more time", DictationMode::Code), "one more time");
+        assert_eq!(dictate("say literal period", DictationMode::Prose), "Say period");
+        // A trailing "literal" has nothing to escape
+        assert_eq!(dictate("literal", DictationMode::Code), "literal");
+    }
+
+    #[test]
+    fn spell_mode_joins_letters() {
+        assert_eq!(dictate("spell a b c", DictationMode::Code), "abc");
+        assert_eq!(dictate("spell capital foxtrot oscar oscar two", DictationMode::Code), "Foo2");
+        assert_eq!(dictate("spell k u b e c t l get pods", DictationMode::Code), "kubectl get pods");
+        assert_eq!(dictate("spell x ray y", DictationMode::Code), "xy");
+        assert_eq!(dictate("spell alpha bravo", DictationMode::Prose), "ab");
+        assert_eq!(dictate("spell the word", DictationMode::Code), "spell the word");
+    }
+
+
+    #[test]
+    fn builtin_snippets_expand_documented_examples() {
+        let profiles = Profiles::load(&Config::default(), None).unwrap();
+        for (profile, spoken, code) in [
+            ("rust", "function named foo taking x", "fn foo(x)"),
+            ("rust", "function named parse line taking input and count", "fn parse_line(input, count)"),
+            ("rust", "struct named http client", "struct HttpClient"),
+            ("python", "function named foo taking x", "def foo(x):"),
+            ("python", "class named http client", "class HttpClient:"),
+            ("javascript", "function named parse line", "function parseLine()"),
+            ("javascript", "arrow function taking a and b", "(a, b) =>"),
+        ] {
+            let profile = profiles.get(profile).unwrap();
+            assert_eq!(format_code_dictation(spoken, profile), code, "{}", spoken);
+        }
+    }
+
+    #[test]
+    fn keyword_casing_leaves_variable_names_alone() {
+        let profiles = Profiles::load(&Config::default(), None).unwrap();
+        let rust = profiles.get("rust").unwrap();
+        assert_eq!(format_code_dictation("let result equals ok", rust), "let result = ok");
+        assert_eq!(format_code_dictation("let name equals string", rust), "let name = string");
+        let python = profiles.get("python").unwrap();
+        assert_eq!(format_code_dictation("result equals none", python), "result = None");
+    }
+
+
+    #[test]
+    fn snippets_fill_fields_and_place_the_cursor() {
+        let cursor = CURSOR_MARKER.to_string();
+        let cases = [
+            ("insert todo {note}", "// TODO: {note:raw}", "insert todo fix the parser", "// TODO: fix the parser"),
+            ("new type {name}", "type {name:pascal} = {value}", "new type user id", "type UserId = $"),
+            ("call {name} with {args}", "{name}({args})$0;", "call parse line with input and count", "parse_line(input, count)$;"),
+            ("test {name}", "#[test]\nfn {name}() {\n    $0\n}", "test parses empty input", "#[test]\nfn parses_empty_input() {\n    $\n}"),
+            ("pair", "({first}, {second})", "pair", "($, )"),
+        ];
+        for (trigger, template, spoken, expanded) in cases {
+            let snippet = Snippet::new(trigger, template).unwrap();
+            let text = snippet.expand(spoken, IdentifierCase::Snake).unwrap();
+            assert_eq!(text, expanded.replace('$', &cursor), "{}", spoken);
+        }
+        assert!(Snippet::new("insert todo {note}", "").unwrap().expand("insert nothing", IdentifierCase::Snake).is_none());
+    }
+
+    #[test]
+    fn cursor_moves_skip_indentation_after_the_cursor_line() {
+        assert_eq!(cursor_moves(""), (0, 0));
+        assert_eq!(cursor_moves(");"), (0, 2));
+        assert_eq!(cursor_moves("\n}"), (1, 0));
+        assert_eq!(cursor_moves(" }\n    more\n}"), (2, 2));
+    }
+
+    #[test]
+    fn output_continues_from_the_snippet_cursor() {
+        let mut output = OutputContext::new();
+        assert_eq!(output.join("foo"), "foo");
+        let snippet = format!("bar({})", CURSOR_MARKER);
+        assert_eq!(output.join(&snippet), format!(" {}", snippet));
+        // The cursor is right after "(", so no space is added
+        assert_eq!(output.join("x"), "x");
+        assert_eq!(output.join(", y"), ", y");
+
+        let mut output = OutputContext::new();
+        output.join(&format!("x = {}.", CURSOR_MARKER));
+        assert!(!output.at_sentence_start());
+        assert_eq!(output.join("1"), "1");
+    }
+
+
+    #[test]
+    fn repeated_numbers_are_kept_in_the_model_language() {
+        let german = DisfluencyFilter::new(&CleanupConfig::default(), "de");
+        assert_eq!(german.clean("im jahr zwanzig zwanzig", DictationMode::Prose), "im jahr zwanzig zwanzig");
+        assert_eq!(german.clean("ich will ich will gehen", DictationMode::Prose), "ich will gehen");
+    }
 }
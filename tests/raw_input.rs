@@ -0,0 +1,99 @@
+//! Pipes raw PCM into `scriba --input -` and checks what is transcribed.
+//! These tests need an extracted Vosk model, set `SCRIBA_TEST_MODEL` to its
+//! directory, and are ignored by default: run them with
+//! `cargo test -- --ignored`. The transcription test also needs
+//! `SCRIBA_TEST_AUDIO`, the `test.wav` clip from the Vosk examples.
+
+#![cfg(unix)]
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Digits read in the Vosk example clip
+const TEST_AUDIO_DIGITS: &str = "100019021001803";
+
+fn env_path(name: &str) -> PathBuf {
+    std::env::var_os(name)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| panic!("{} is not set", name))
+}
+
+/// Configuration directory containing only the test model
+fn config_home(name: &str) -> PathBuf {
+    let model = env_path("SCRIBA_TEST_MODEL");
+    let home = std::env::temp_dir().join(format!("scriba-e2e-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&home);
+    let models = home.join("scriba/models");
+    std::fs::create_dir_all(&models).unwrap();
+    std::os::unix::fs::symlink(std::fs::canonicalize(&model).unwrap(), models.join(model.file_name().unwrap())).unwrap();
+    home
+}
+
+/// Run scriba on `audio` piped to stdin and return its output
+fn transcribe(name: &str, audio: &[u8], args: &[&str]) -> String {
+    let home = config_home(name);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_scriba"))
+        .args(["--input", "-", "--no-typing"])
+        .args(args)
+        .env("XDG_CONFIG_HOME", &home)
+        .env("XDG_RUNTIME_DIR", &home)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(audio).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let log = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success(), "scriba failed:\n{}", log);
+
+    std::fs::remove_dir_all(home).unwrap();
+    log
+}
+
+/// Digits of the transcriptions in `log`, whether they were typed as
+/// words or converted to digits
+fn transcribed_digits(log: &str) -> String {
+    const DIGITS: &[&str] = &["zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine"];
+    log.lines()
+        .filter_map(|line| line.split_once("Transcription (confidence: ")?.1.split_once("): ").map(|(_, text)| text))
+        .flat_map(|text| text.split(|c: char| !c.is_alphanumeric()).map(str::to_lowercase).collect::<Vec<_>>())
+        .map(|word| match DIGITS.iter().position(|digit| *digit == word) {
+            Some(digit) => digit.to_string(),
+            None if word == "oh" || word == "o" => "0".to_string(),
+            None => word.chars().filter(char::is_ascii_digit).collect(),
+        })
+        .collect()
+}
+
+#[test]
+#[ignore = "needs SCRIBA_TEST_MODEL"]
+fn reads_s16le_from_stdin() {
+    // One second of silence at 16kHz
+    let audio = vec![0u8; 16000 * 2];
+    let log = transcribe("s16le", &audio, &[]);
+    assert!(log.contains("Reading S16le audio with 1 channel(s) from stdin"), "{}", log);
+}
+
+#[test]
+#[ignore = "needs SCRIBA_TEST_MODEL"]
+fn reads_stereo_f32le_from_stdin() {
+    let audio: Vec<u8> = std::iter::repeat_n(0.0f32.to_le_bytes(), 16000 * 2).flatten().collect();
+    let log = transcribe("f32le", &audio, &["--input-format", "f32le", "--input-channels", "2"]);
+    assert!(log.contains("Reading F32le audio with 2 channel(s) from stdin"), "{}", log);
+}
+
+#[test]
+#[ignore = "needs SCRIBA_TEST_MODEL and SCRIBA_TEST_AUDIO"]
+fn transcribes_speech_from_stdin() {
+    let mut reader = hound::WavReader::open(env_path("SCRIBA_TEST_AUDIO")).unwrap();
+    let spec = reader.spec();
+    assert_eq!((spec.channels, spec.bits_per_sample), (1, 16), "expected 16-bit mono audio");
+    let audio: Vec<u8> = reader.samples::<i16>().flat_map(|sample| sample.unwrap().to_le_bytes()).collect();
+
+    let sample_rate = spec.sample_rate.to_string();
+    let log = transcribe("speech", &audio, &["--mode", "prose", "--sample-rate", &sample_rate]);
+    assert_eq!(transcribed_digits(&log), TEST_AUDIO_DIGITS, "{}", log);
+}